@@ -0,0 +1,130 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Fuzz targets for every decoder that reads bytes from a peer. The crate builds GiralNet's
+//! own modules, so the targets exercise exactly the code nodes, proxies and directories run.
+//! Each function here must return normally for any input; a panic, or an allocation larger
+//! than the limits in [`protocol::MessageKind`], is a bug.
+//!
+//! Run a target with `cargo +nightly fuzz run <target>` from this directory.
+
+#![allow(dead_code)]
+
+#[path = "../../src/config.rs"]
+mod config;
+#[path = "../../src/crypto.rs"]
+mod crypto;
+#[path = "../../src/directory.rs"]
+mod directory;
+#[path = "../../src/directory_protocol.rs"]
+mod directory_protocol;
+#[path = "../../src/directory_store.rs"]
+mod directory_store;
+#[path = "../../src/exit_policy.rs"]
+mod exit_policy;
+#[path = "../../src/flow.rs"]
+mod flow;
+#[path = "../../src/link.rs"]
+mod link;
+#[path = "../../src/protocol.rs"]
+mod protocol;
+#[path = "../../src/tls_client.rs"]
+mod tls_client;
+#[path = "../../src/tls_setup.rs"]
+mod tls_setup;
+
+use directory_protocol::{DirectoryRequest, DirectoryResponse};
+use link::{LinkCell, LINK_CELL_LEN};
+use protocol::{Cell, CircuitMessage, MessageKind, NtorHandshake, NtorReply, ProtocolSupport, CELL_LEN};
+use std::future::Future;
+use std::task::{Context, Poll, Waker};
+
+const KINDS: [MessageKind; 5] = [
+    MessageKind::Hello,
+    MessageKind::DirectoryRequest,
+    MessageKind::DirectoryResponse,
+    MessageKind::Invite,
+    MessageKind::Cell,
+];
+
+/// Runs a read from an in-memory buffer, which never has to wait.
+fn ready<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("reading from a buffer never waits"),
+    }
+}
+
+/// Copies `data` into a fixed-size buffer, zero-padded or cut short as needed.
+fn fixed<const N: usize>(data: &[u8]) -> [u8; N] {
+    let mut bytes = [0u8; N];
+    let len = data.len().min(N);
+    bytes[..len].copy_from_slice(&data[..len]);
+    bytes
+}
+
+/// Length-prefixed frames of every kind.
+pub fn frame(data: &[u8]) {
+    for kind in KINDS {
+        let mut reader = data;
+        if let Ok(frame) = ready(protocol::read_frame(&mut reader, kind)) {
+            assert!(frame.len() <= kind.max_len(), "{} frame over its limit", kind);
+        }
+    }
+}
+
+/// The hello opening directory and link connections.
+pub fn hello(data: &[u8]) {
+    if let Some(support) = ProtocolSupport::decode_hello(data) {
+        let _ = support.negotiate();
+    }
+}
+
+/// Relay cells, as a hop finds them once its layer is removed.
+pub fn cell(data: &[u8]) {
+    if let Ok(cell) = Cell::decode(&fixed::<CELL_LEN>(data)) {
+        let _ = CircuitMessage::try_from(cell);
+    }
+}
+
+/// Cells on a link connection.
+pub fn link_cell(data: &[u8]) {
+    if let Ok(cell) = LinkCell::decode(&fixed::<LINK_CELL_LEN>(data)) {
+        let _ = cell.destroy_reason();
+    }
+}
+
+/// Requests a directory reads from members.
+pub fn directory_request(data: &[u8]) {
+    let _ = protocol::decode::<DirectoryRequest>(data, MessageKind::DirectoryRequest);
+}
+
+/// Responses members read from the directory.
+pub fn directory_response(data: &[u8]) {
+    let _ = protocol::decode::<DirectoryResponse>(data, MessageKind::DirectoryResponse);
+}
+
+/// The body of an invite token, once its HMAC is checked.
+pub fn invite(data: &[u8]) {
+    let _ = protocol::decode::<directory::Invite>(data, MessageKind::Invite);
+}
+
+/// The ntor handshake in a `Create` cell and the reply in a `Created` cell.
+pub fn ntor(data: &[u8]) {
+    let _ = protocol::decode::<NtorHandshake>(data, MessageKind::Cell);
+    let _ = protocol::decode::<NtorReply>(data, MessageKind::Cell);
+}
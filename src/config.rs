@@ -37,25 +37,225 @@ pub enum Mode {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DirectoryConfig {
     pub listen_addr: String,
+    /// Keys the invite tokens the directory issues. Only the directory needs it.
+    #[serde(default)]
     pub secret: String,
+    /// Key the directory signs consensus documents with. Its `.pub` half must be given to every proxy.
+    #[serde(default = "default_signing_key_file")]
+    pub signing_key_file: String,
+    /// Periodically connect to every registered node and leave unreachable ones out of the consensus.
+    #[serde(default)]
+    pub probe_nodes: bool,
+    /// Where the registry is saved so it survives restarts.
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    /// Member IDs refused by the directory, one per line.
+    #[serde(default = "default_revocation_file")]
+    pub revocation_file: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NodeConfig {
     pub listen_addr: String,
+    /// Address published to the directory, for when proxies and other relays reach this
+    /// node somewhere other than `listen_addr`, such as behind NAT. Empty publishes
+    /// `listen_addr`, which then must name a specific interface.
+    #[serde(default)]
+    pub advertise_addr: String,
     pub key_file: String,
+    /// Ask for a passphrase at startup and keep the identity key encrypted on disk.
+    #[serde(default)]
+    pub encrypt_key: bool,
+    /// Advertised bandwidth in bytes per second, 0 to leave it undeclared.
+    #[serde(default)]
+    pub bandwidth: u64,
+    /// Key enrolled with the directory that authenticates this node's registrations.
+    #[serde(default = "default_member_key_file")]
+    pub member_key_file: String,
+    /// Client certificate the directory issued for the member key at enrollment.
+    #[serde(default = "default_member_cert_file")]
+    pub member_cert_file: String,
+    /// Destinations this node will connect to as an exit, as `accept|reject ADDRESS[/BITS]:PORTS`
+    /// rules. The first matching rule decides and unmatched destinations are rejected, so
+    /// the default of no rules rejects everything.
+    #[serde(default = "default_exit_policy")]
+    pub exit_policy: Vec<String>,
+    /// Reject loopback, private and link-local ranges ahead of `exit_policy`.
+    #[serde(default = "default_true")]
+    pub exit_reject_private: bool,
+    /// `Guard` and `Exit` let proxies use the node as the first or last hop; with neither
+    /// it is relay-only. Other entries are labels published with the node. Exiting is
+    /// opt-in, so the default is `Guard` alone.
+    #[serde(default = "default_node_flags")]
+    pub flags: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProxyConfig {
     pub listen_addr: String,
+    /// Pinned public key of the directory, used to verify every consensus it hands out.
+    #[serde(default = "default_directory_key_file")]
+    pub directory_key_file: String,
+    /// Key enrolled with the directory that authenticates this proxy's node list requests.
+    #[serde(default = "default_member_key_file")]
+    pub member_key_file: String,
+    /// Client certificate the directory issued for the member key at enrollment.
+    #[serde(default = "default_member_cert_file")]
+    pub member_cert_file: String,
+    /// Extra SOCKS5 listeners besides `listen_addr`. Leave `listen_addr` empty to only use these.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Isolation of the `listen_addr` listener; see [`ListenerConfig::isolate`].
+    #[serde(default = "default_isolation")]
+    pub isolate: Vec<Isolation>,
+    /// Never put two nodes from the same /16 (IPv4) or /32 (IPv6) network in one circuit,
+    /// judged by the addresses nodes register. Turn off only for test networks whose nodes
+    /// all run on one host or in one /16 network.
+    #[serde(default = "default_true")]
+    pub enforce_distinct_subnets: bool,
+    /// Where the chosen entry guards are saved between runs.
+    #[serde(default = "default_guard_state_file")]
+    pub guard_state_file: String,
+    /// Days an entry guard is kept before it is replaced by a new one.
+    #[serde(default = "default_guard_rotation_days")]
+    pub guard_rotation_days: u64,
+    /// Hops in every circuit. 3 is the minimum at which no single relay sees both who you
+    /// are and where you connect; 1 and 2 are for testing or low-latency use only.
+    #[serde(default = "default_circuit_length")]
+    pub circuit_length: usize,
+    /// Clean circuits kept built per circuit name, ready for new streams.
+    #[serde(default = "default_circuit_pool_size")]
+    pub circuit_pool_size: usize,
+    /// Seconds a circuit keeps taking new streams after its first one. Streams already on
+    /// it are unaffected.
+    #[serde(default = "default_max_circuit_dirtiness_secs")]
+    pub max_circuit_dirtiness_secs: u64,
+}
+
+/// Shortest circuit that keeps the entry from learning the destination and the exit from
+/// learning the client.
+pub const SAFE_CIRCUIT_LENGTH: usize = 3;
+
+/// Longest circuit allowed. Every hop adds latency and a layer to the onion, and beyond this
+/// the extra hops add nothing but delay.
+pub const MAX_CIRCUIT_LENGTH: usize = 8;
+
+/// Checks `circuit_length` is between 1 and [`MAX_CIRCUIT_LENGTH`].
+pub fn validate_circuit_length(length: usize) -> Result<(), String> {
+    if length == 0 || length > MAX_CIRCUIT_LENGTH {
+        return Err(format!("circuit_length must be between 1 and {}, not {}.", MAX_CIRCUIT_LENGTH, length));
+    }
+    Ok(())
+}
+
+/// Circuit used by `listen_addr` and by listeners that don't name one.
+pub const DEFAULT_CIRCUIT: &str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListenerConfig {
+    /// `ip:port` (`[ip]:port` for IPv6), or `unix:/path/to/socket` for a Unix domain socket.
+    pub address: String,
+    /// Listeners naming the same circuit share it; different names never share a circuit.
+    #[serde(default = "default_circuit")]
+    pub circuit: String,
+    /// What keeps this listener's streams on separate circuits.
+    #[serde(default = "default_isolation")]
+    pub isolate: Vec<Isolation>,
+}
+
+/// A property of a stream that, when it differs, keeps two streams off the same circuit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    /// The SOCKS username and password.
+    Auth,
+    /// The client's IP address.
+    ClientAddr,
+    /// The client's source port, which in practice gives every connection its own circuit.
+    ClientPort,
+    /// The destination host.
+    DestHost,
+    /// The listener the client connected to.
+    Listener,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TlsConfig {
+    /// The team CA certificate. Everyone trusts it; the directory also checks member certificates against it.
     pub ca_cert_path: String,
     pub cert_path: String,
     pub key_path: String,
+    /// Key of the team CA, only needed by the directory to issue member certificates.
+    #[serde(default = "default_ca_key_path")]
+    pub ca_key_path: String,
+}
+
+fn default_signing_key_file() -> String {
+    "directory_signing_key".into()
+}
+
+fn default_state_file() -> String {
+    "directory_state.bin".into()
+}
+
+fn default_revocation_file() -> String {
+    "revoked_members.txt".into()
+}
+
+fn default_member_key_file() -> String {
+    "member_key".into()
+}
+
+fn default_member_cert_file() -> String {
+    "member_cert.pem".into()
+}
+
+fn default_exit_policy() -> Vec<String> {
+    Vec::new()
+}
+
+fn default_guard_state_file() -> String {
+    "guard_state.bin".into()
+}
+
+fn default_guard_rotation_days() -> u64 {
+    30
+}
+
+fn default_circuit_length() -> usize {
+    SAFE_CIRCUIT_LENGTH
+}
+
+fn default_circuit_pool_size() -> usize {
+    2
+}
+
+fn default_max_circuit_dirtiness_secs() -> u64 {
+    10 * 60
+}
+
+fn default_node_flags() -> Vec<String> {
+    vec!["Guard".into()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_circuit() -> String {
+    DEFAULT_CIRCUIT.into()
+}
+
+fn default_isolation() -> Vec<Isolation> {
+    vec![Isolation::Auth]
+}
+
+fn default_ca_key_path() -> String {
+    "ca_key.pem".into()
+}
+
+fn default_directory_key_file() -> String {
+    "directory_signing_key.pub".into()
 }
 
 pub fn load_config() -> Result<Config, Box<dyn Error>> {
@@ -14,48 +14,124 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use rsa::{RsaPrivateKey, RsaPublicKey, Pkcs1v15Encrypt};
-use rsa::pkcs8::{EncodePublicKey, DecodePublicKey, LineEnding};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use std::error::Error;
 use std::fs;
-use aes_gcm::aead::{Aead, KeyInit};
-use aes_gcm::{Aes256Gcm, Key, Nonce};
+use std::io::Write;
+use std::path::Path;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::protocol::{RELAY_DIGEST_RANGE, RELAY_HEADER_LEN};
 
 const RSA_BITS: usize = 2048;
 const AES_KEY_SIZE: usize = 32;
 
+const NTOR_PROTOID: &[u8] = b"giralnet-ntor-x25519-sha256-1";
+const NTOR_T_KEY: &[u8] = b"giralnet-ntor-x25519-sha256-1:key_extract";
+const NTOR_T_VERIFY: &[u8] = b"giralnet-ntor-x25519-sha256-1:verify";
+const NTOR_T_MAC: &[u8] = b"giralnet-ntor-x25519-sha256-1:mac";
+const NTOR_T_EXPAND: &[u8] = b"giralnet-ntor-x25519-sha256-1:key_expand";
+
+type HmacSha256 = Hmac<Sha256>;
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// One direction of the relay crypto shared with a hop: a running AES-256-CTR keystream,
+/// so every cell uses fresh counter blocks, and a running digest over every cell that
+/// hop originated or was the destination of.
+pub struct RelayLayer {
+    cipher: Aes256Ctr,
+    digest: Sha256,
+}
+
+/// Forward (proxy -> exit) and backward (exit -> proxy) layers shared with one hop.
+pub struct RelayCrypto {
+    pub forward: RelayLayer,
+    pub backward: RelayLayer,
+}
+
+/// Server's answer to an ntor handshake together with the keys it derived.
+pub struct NtorServerReply {
+    pub server_key: [u8; 32],
+    pub auth: [u8; 32],
+    pub relay: RelayCrypto,
+}
+
+/// Client half of an ntor handshake with a single hop, kept until its reply arrives.
+pub struct NtorClientState {
+    secret: StaticSecret,
+    public: [u8; 32],
+    node_id: [u8; 32],
+    onion_key: [u8; 32],
+}
+
 pub fn generate_rsa_keys() -> RsaPrivateKey {
     RsaPrivateKey::new(&mut OsRng, RSA_BITS).expect("Failed to generate a key")
 }
 
-pub fn rsa_encrypt(pub_key: &RsaPublicKey, data: &[u8]) -> Vec<u8> {
-    pub_key.encrypt(&mut OsRng, Pkcs1v15Encrypt, data).expect("Failed to encrypt")
+/// Signs `data` with RSASSA-PKCS1-v1_5 over SHA-256.
+pub fn sign(priv_key: &RsaPrivateKey, data: &[u8]) -> Vec<u8> {
+    SigningKey::<Sha256>::new(priv_key.clone()).sign(data).to_vec()
 }
 
-pub fn rsa_decrypt(priv_key: &RsaPrivateKey, data: &[u8]) -> Vec<u8> {
-    priv_key.decrypt(Pkcs1v15Encrypt, data).expect("Failed to decrypt")
+pub fn verify(pub_key: &RsaPublicKey, data: &[u8], signature: &[u8]) -> Result<(), Box<dyn Error>> {
+    let signature = Signature::try_from(signature)?;
+    VerifyingKey::<Sha256>::new(pub_key.clone()).verify(data, &signature)?;
+    Ok(())
 }
 
-pub fn generate_aes_key() -> [u8; AES_KEY_SIZE] {
-    rand::random()
-}
+/// Loads a long-term identity key (a node's, a member's, or the directory's signing key) from
+/// `file_path`, generating and saving a new one on first run. The key is stored as PKCS#8 PEM, encrypted when a passphrase is given.
+pub fn load_or_create_identity_key(file_path: &str, passphrase: Option<&str>) -> Result<RsaPrivateKey, Box<dyn Error>> {
+    if !Path::new(file_path).exists() {
+        let key = generate_rsa_keys();
+        save_private_key(&key, file_path, passphrase)?;
+        return Ok(key);
+    }
 
-pub fn aes_encrypt(key: &[u8; AES_KEY_SIZE], data: &[u8]) -> (Vec<u8>, Vec<u8>) {
-    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
-    
-    let nonce_bytes = rand::random::<[u8; 12]>();
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    
-    let ciphertext = cipher.encrypt(nonce, data.as_ref()).expect("AES encryption failed");
-    (ciphertext, nonce_bytes.to_vec())
+    let pem = fs::read_to_string(file_path)?;
+    if pem.contains("BEGIN ENCRYPTED PRIVATE KEY") {
+        let passphrase = passphrase.ok_or("The identity key is encrypted, but no passphrase was provided")?;
+        RsaPrivateKey::from_pkcs8_encrypted_pem(&pem, passphrase)
+            .map_err(|_| "Failed to decrypt the identity key. Is the passphrase correct?".into())
+    } else {
+        RsaPrivateKey::from_pkcs8_pem(&pem).map_err(|e| format!("Failed to decode the identity key: {}", e).into())
+    }
 }
 
-pub fn aes_decrypt(key: &[u8; AES_KEY_SIZE], nonce: &[u8], ciphertext: &[u8]) -> Vec<u8> {
-    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+pub fn save_private_key(priv_key: &RsaPrivateKey, file_path: &str, passphrase: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let pem = match passphrase {
+        Some(passphrase) => priv_key.to_pkcs8_encrypted_pem(&mut OsRng, passphrase, LineEnding::LF)?,
+        None => priv_key.to_pkcs8_pem(LineEnding::LF)?,
+    };
+    write_private_file(file_path, pem.as_bytes())
+}
 
-    let nonce = Nonce::from_slice(nonce);
+/// Writes `contents` to a fresh owner-only temporary file and renames it over `file_path`,
+/// so secrets are never world-readable and a crash never leaves a truncated file behind.
+pub fn write_private_file(file_path: &str, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{}.tmp", file_path);
+    let _ = fs::remove_file(&tmp_path);
 
-    cipher.decrypt(nonce, ciphertext.as_ref()).expect("AES decryption failed")
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, file_path)?;
+    Ok(())
 }
 
 pub fn save_public_key(pub_key: &RsaPublicKey, file_path: &str) {
@@ -63,7 +139,279 @@ pub fn save_public_key(pub_key: &RsaPublicKey, file_path: &str) {
     fs::write(file_path, pem).expect("Failed to write public key to file");
 }
 
-pub fn load_public_key(file_path: &str) -> RsaPublicKey {
-    let pem = fs::read_to_string(file_path).expect("Failed to read public key from file");
-    RsaPublicKey::from_public_key_pem(&pem).expect("Failed to decode public key")
-}
\ No newline at end of file
+pub fn load_public_key(file_path: &str) -> Result<RsaPublicKey, Box<dyn Error>> {
+    let pem = fs::read_to_string(file_path).map_err(|e| format!("Failed to read public key from {}: {}", file_path, e))?;
+    Ok(RsaPublicKey::from_public_key_pem(&pem)?)
+}
+
+/// Identity fingerprint of a node: SHA-256 over the DER encoding of its RSA public key.
+pub fn node_id(pub_key: &RsaPublicKey) -> [u8; 32] {
+    let der = pub_key.to_public_key_der().expect("Failed to encode public key");
+    Sha256::digest(der.as_bytes()).into()
+}
+
+pub fn generate_onion_key() -> StaticSecret {
+    StaticSecret::random_from_rng(OsRng)
+}
+
+pub fn onion_public_key(secret: &StaticSecret) -> [u8; 32] {
+    PublicKey::from(secret).to_bytes()
+}
+
+/// Starts an ntor handshake against a hop identified by `node_id` and its published onion key.
+pub fn ntor_client_start(node_id: [u8; 32], onion_key: [u8; 32]) -> NtorClientState {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret).to_bytes();
+    NtorClientState { secret, public, node_id, onion_key }
+}
+
+impl NtorClientState {
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+
+    /// Verifies the hop's reply and derives the session keys shared with it.
+    pub fn finish(self, server_key: &[u8; 32], auth: &[u8; 32]) -> Result<RelayCrypto, Box<dyn Error>> {
+        let xy = dh(&self.secret, server_key)?;
+        let xb = dh(&self.secret, &self.onion_key)?;
+        let secret_input = ntor_secret_input(&xy, &xb, &self.node_id, &self.onion_key, &self.public, server_key);
+
+        let mut mac = ntor_auth_mac(&secret_input, &self.node_id, &self.onion_key, &self.public, server_key);
+        mac.update(b"Server");
+        mac.verify_slice(auth).map_err(|_| "ntor handshake authentication failed")?;
+
+        Ok(ntor_session_keys(&secret_input))
+    }
+}
+
+/// Answers an ntor handshake with a fresh ephemeral key and an authenticator proving
+/// possession of the onion key.
+pub fn ntor_server_respond(onion_secret: &StaticSecret, node_id: &[u8; 32], client_key: &[u8; 32]) -> Result<NtorServerReply, Box<dyn Error>> {
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let server_key = PublicKey::from(&ephemeral).to_bytes();
+    let onion_key = onion_public_key(onion_secret);
+
+    let xy = dh(&ephemeral, client_key)?;
+    let xb = dh(onion_secret, client_key)?;
+    let secret_input = ntor_secret_input(&xy, &xb, node_id, &onion_key, client_key, &server_key);
+
+    let mut mac = ntor_auth_mac(&secret_input, node_id, &onion_key, client_key, &server_key);
+    mac.update(b"Server");
+    let auth: [u8; 32] = mac.finalize().into_bytes().into();
+
+    Ok(NtorServerReply { server_key, auth, relay: ntor_session_keys(&secret_input) })
+}
+
+impl RelayLayer {
+    fn new(key: &[u8], digest_seed: &[u8]) -> Self {
+        Self {
+            cipher: Aes256Ctr::new(key.into(), &[0u8; 16].into()),
+            digest: Sha256::new_with_prefix(digest_seed),
+        }
+    }
+
+    /// Adds or removes this layer of encryption. Relays call this on every cell passing
+    /// through; the keystream keeps running across cells in the same direction.
+    pub fn apply(&mut self, cell: &mut [u8]) {
+        self.cipher.apply_keystream(cell);
+    }
+
+    /// Stamps an unencrypted cell originating at this hop's end of the circuit with the
+    /// running digest. The `recognized` and digest fields must still be zero.
+    pub fn seal(&mut self, cell: &mut [u8]) {
+        self.digest.update(&*cell);
+        let digest = self.digest.clone().finalize();
+        cell[RELAY_DIGEST_RANGE].copy_from_slice(&digest[..RELAY_DIGEST_RANGE.len()]);
+    }
+
+    /// Checks whether a decrypted cell was sealed for this hop. The running digest only
+    /// advances when the cell is recognized, so cells meant for later hops leave it intact.
+    pub fn recognize(&mut self, cell: &[u8]) -> bool {
+        if cell.len() < RELAY_HEADER_LEN || cell[..RELAY_DIGEST_RANGE.start].iter().any(|&b| b != 0) {
+            return false;
+        }
+        let mut digest = self.digest.clone();
+        digest.update(&cell[..RELAY_DIGEST_RANGE.start]);
+        digest.update([0u8; RELAY_DIGEST_RANGE.end - RELAY_DIGEST_RANGE.start]);
+        digest.update(&cell[RELAY_DIGEST_RANGE.end..]);
+        if digest.clone().finalize()[..RELAY_DIGEST_RANGE.len()] != cell[RELAY_DIGEST_RANGE] {
+            return false;
+        }
+        self.digest = digest;
+        true
+    }
+}
+
+fn dh(secret: &StaticSecret, public: &[u8; 32]) -> Result<[u8; 32], Box<dyn Error>> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+        return Err("Rejected a low-order public key in the handshake".into());
+    }
+    Ok(shared.to_bytes())
+}
+
+fn ntor_secret_input(xy: &[u8; 32], xb: &[u8; 32], node_id: &[u8; 32], onion_key: &[u8; 32], client_key: &[u8; 32], server_key: &[u8; 32]) -> Vec<u8> {
+    [xy.as_slice(), xb, node_id, onion_key, client_key, server_key, NTOR_PROTOID].concat()
+}
+
+fn ntor_auth_mac(secret_input: &[u8], node_id: &[u8; 32], onion_key: &[u8; 32], client_key: &[u8; 32], server_key: &[u8; 32]) -> HmacSha256 {
+    let verify = hmac_sha256(NTOR_T_VERIFY, secret_input);
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(NTOR_T_MAC).expect("HMAC accepts any key length");
+    for part in [verify.as_slice(), node_id, onion_key, server_key, client_key, NTOR_PROTOID] {
+        mac.update(part);
+    }
+    mac
+}
+
+fn ntor_session_keys(secret_input: &[u8]) -> RelayCrypto {
+    let key_seed = hmac_sha256(NTOR_T_KEY, secret_input);
+    let hkdf = Hkdf::<Sha256>::from_prk(&key_seed).expect("PRK has the digest length");
+    let mut okm = [0u8; 4 * AES_KEY_SIZE];
+    hkdf.expand(NTOR_T_EXPAND, &mut okm).expect("Requested key material is within HKDF limits");
+
+    let (forward_digest, rest) = okm.split_at(AES_KEY_SIZE);
+    let (backward_digest, rest) = rest.split_at(AES_KEY_SIZE);
+    let (forward_key, backward_key) = rest.split_at(AES_KEY_SIZE);
+    RelayCrypto {
+        forward: RelayLayer::new(forward_key, forward_digest),
+        backward: RelayLayer::new(backward_key, backward_digest),
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Checks an HMAC-SHA256 tag in constant time.
+pub fn hmac_sha256_verify(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.verify_slice(tag).is_ok()
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err("Invalid hex string".into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| "Invalid hex string".into()))
+        .collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::CELL_LEN;
+
+    /// Both ends of an ntor handshake with a fresh hop.
+    fn handshake() -> (RelayCrypto, RelayCrypto) {
+        let onion = generate_onion_key();
+        let node_id = random_bytes();
+        let client = ntor_client_start(node_id, onion_public_key(&onion));
+        let reply = ntor_server_respond(&onion, &node_id, &client.public_key()).unwrap();
+        (client.finish(&reply.server_key, &reply.auth).unwrap(), reply.relay)
+    }
+
+    fn plain_cell(tag: u8) -> [u8; CELL_LEN] {
+        let mut cell = [tag; CELL_LEN];
+        cell[..RELAY_HEADER_LEN].fill(0);
+        cell
+    }
+
+    #[test]
+    fn ntor_ends_derive_the_same_keys() {
+        let (mut client, mut server) = handshake();
+        for (sender, receiver) in [(&mut client.forward, &mut server.forward), (&mut server.backward, &mut client.backward)] {
+            for tag in 1..=3 {
+                let mut cell = plain_cell(tag);
+                sender.seal(&mut cell);
+                let sealed = cell;
+                sender.apply(&mut cell);
+                assert_ne!(cell, sealed);
+                receiver.apply(&mut cell);
+                assert_eq!(cell, sealed);
+                assert!(receiver.recognize(&cell));
+            }
+        }
+    }
+
+    #[test]
+    fn ntor_fails_against_the_wrong_identity_or_onion_key() {
+        let onion = generate_onion_key();
+        let node_id = random_bytes();
+        let respond = |client: &NtorClientState| ntor_server_respond(&onion, &node_id, &client.public_key()).unwrap();
+
+        let wrong_identity = ntor_client_start(random_bytes(), onion_public_key(&onion));
+        let reply = respond(&wrong_identity);
+        assert!(wrong_identity.finish(&reply.server_key, &reply.auth).is_err());
+
+        let wrong_onion_key = ntor_client_start(node_id, onion_public_key(&generate_onion_key()));
+        let reply = respond(&wrong_onion_key);
+        assert!(wrong_onion_key.finish(&reply.server_key, &reply.auth).is_err());
+
+        let right = ntor_client_start(node_id, onion_public_key(&onion));
+        let reply = respond(&right);
+        let mut auth = reply.auth;
+        auth[0] ^= 1;
+        assert!(right.finish(&reply.server_key, &auth).is_err());
+    }
+
+    /// Removes one layer per hop until a hop recognizes the cell, as relays do, and returns
+    /// which hop that was.
+    fn deliver(hops: &mut [RelayCrypto], cell: &mut [u8; CELL_LEN]) -> Option<usize> {
+        for (i, hop) in hops.iter_mut().enumerate() {
+            hop.forward.apply(cell);
+            if hop.forward.recognize(cell) {
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn each_hop_recognizes_only_cells_sealed_for_it() {
+        let (mut proxy, mut hops): (Vec<RelayCrypto>, Vec<RelayCrypto>) = (0..3).map(|_| handshake()).unzip();
+        for target in [2, 0, 1, 2] {
+            let mut cell = plain_cell(target as u8 + 1);
+            proxy[target].forward.seal(&mut cell);
+            for hop in proxy[..=target].iter_mut().rev() {
+                hop.forward.apply(&mut cell);
+            }
+            assert_eq!(deliver(&mut hops, &mut cell), Some(target));
+            assert_eq!(cell[RELAY_HEADER_LEN..], plain_cell(target as u8 + 1)[RELAY_HEADER_LEN..]);
+        }
+    }
+
+    #[test]
+    fn tampered_cells_are_not_recognized() {
+        let (mut proxy, mut hops): (Vec<RelayCrypto>, Vec<RelayCrypto>) = (0..3).map(|_| handshake()).unzip();
+        let mut cell = plain_cell(7);
+        proxy[2].forward.seal(&mut cell);
+        for hop in proxy.iter_mut().rev() {
+            hop.forward.apply(&mut cell);
+        }
+        cell[CELL_LEN - 1] ^= 1;
+        assert_eq!(deliver(&mut hops, &mut cell), None);
+
+        // So does a cell whose digest was altered, even with the payload intact.
+        let (mut client, mut server) = handshake();
+        let mut cell = plain_cell(8);
+        client.forward.seal(&mut cell);
+        cell[RELAY_DIGEST_RANGE.start] ^= 1;
+        client.forward.apply(&mut cell);
+        server.forward.apply(&mut cell);
+        assert!(!server.forward.recognize(&cell));
+    }
+}
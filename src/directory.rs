@@ -14,34 +14,137 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use crate::directory_protocol::{DirectoryRequest, DirectoryResponse, NodeInfo};
+use crate::config::{DirectoryConfig, TlsConfig};
+use crate::crypto;
+use crate::directory_store::{self, DirectoryState, Member, NodeEntry};
+use crate::directory_protocol::{self, Consensus, ConsensusEntry, DirectoryRequest, DirectoryResponse, MemberAuth, NodeDescriptor, SignedConsensus, GET_NODES_CONTEXT, HEARTBEAT_INTERVAL_SECS, MAX_CLOCK_SKEW_SECS};
+use crate::protocol::{self, MessageKind, ProtocolSupport};
+use crate::tls_client;
+use crate::tls_setup::{self, TeamCa};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpListener;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
 use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
 
-type NodeList = Arc<Mutex<HashMap<SocketAddr, NodeInfo>>>;
+impl NodeEntry {
+    fn is_live(&self, now: u64) -> bool {
+        self.reachable && now <= self.last_seen + NODE_EXPIRY_SECS
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        now > self.last_seen + NODE_EXPIRY_SECS
+    }
+}
+
+/// The directory's in-memory state together with the file it is persisted to.
+struct Registry {
+    state: DirectoryState,
+    state_file: String,
+}
+
+impl Registry {
+    fn persist(&self) {
+        if let Err(e) = directory_store::save(&self.state_file, &self.state) {
+            eprintln!("[DIR] Failed to save directory state to {}: {}", self.state_file, e);
+        }
+    }
+
+    /// Adds or replaces the entry for the descriptor's address on behalf of an
+    /// authenticated member.
+    fn register(&mut self, descriptor: NodeDescriptor, member_id: String) -> Result<(), Box<dyn Error>> {
+        let address = descriptor.info.address;
+        check_descriptor(&descriptor)?;
+        check_owner(self.state.nodes.get(&address), &member_id)?;
+        let entry = NodeEntry {
+            last_seen: directory_protocol::unix_now(),
+            last_heartbeat: descriptor.info.published,
+            reachable: true,
+            member_id,
+            descriptor,
+        };
+        self.state.nodes.insert(address, entry);
+        self.persist();
+        Ok(())
+    }
+}
+
+type NodeList = Arc<Mutex<Registry>>;
+
+/// Descriptors older than this are refused; nodes publish a fresh one whenever they register.
+const DESCRIPTOR_MAX_AGE_SECS: u64 = 60 * 60;
+const CONSENSUS_FRESH_SECS: u64 = 60 * 60;
+const CONSENSUS_VALID_SECS: u64 = 3 * 60 * 60;
+/// Nodes that miss three heartbeats in a row are dropped from the registry.
+const NODE_EXPIRY_SECS: u64 = 3 * HEARTBEAT_INTERVAL_SECS;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a member has to redeem an invite.
+const INVITE_VALID_SECS: u64 = 7 * 24 * 60 * 60;
 
-pub async fn run(listen_addr: &str, master_secret: &str, cert_path: &str, key_path: &str) -> Result<(), Box<dyn Error>> {
+/// Long-lived secrets and settings shared by every connection.
+struct Authority {
+    secret: String,
+    revocation_file: String,
+    signing_key: RsaPrivateKey,
+    team_ca: TeamCa,
+}
+
+pub async fn run(dir_config: &DirectoryConfig, tls_config: &TlsConfig) -> Result<(), Box<dyn Error>> {
+    let listen_addr = dir_config.listen_addr.as_str();
+    let master_secret = check_secret(&dir_config.secret)?;
+    let signing_key_file = dir_config.signing_key_file.as_str();
     println!("[DIR] Starting Directory Authority on {}...", listen_addr);
 
-    let (certs, key) = load_certs_and_key(cert_path, key_path)?;
+    let signing_key = crypto::load_or_create_identity_key(signing_key_file, None)?;
+    crypto::save_public_key(&signing_key.to_public_key(), &format!("{}.pub", signing_key_file));
+    println!("[DIR] Consensus signing key loaded. Every proxy needs a copy of {}.pub", signing_key_file);
+
+    let team_ca = TeamCa::load(&tls_config.ca_key_path)?;
+    let (certs, key) = load_certs_and_key(&tls_config.cert_path, &tls_config.key_path)?;
+    // Members without a certificate yet may connect, but only to enroll for one.
+    let client_verifier = AllowAnyAnonymousOrAuthenticatedClient::new(tls_client::load_root_store(&tls_config.ca_cert_path)?);
     let config = ServerConfig::builder()
         .with_safe_defaults()
-        .with_no_client_auth()
+        .with_client_cert_verifier(client_verifier.boxed())
         .with_single_cert(certs, key)?;
     let acceptor = TlsAcceptor::from(Arc::new(config));
 
-    let secret = master_secret.to_string();
-    let nodes = Arc::new(Mutex::new(HashMap::new()));
+    let authority = Arc::new(Authority {
+        secret: master_secret.to_string(),
+        revocation_file: dir_config.revocation_file.clone(),
+        signing_key,
+        team_ca,
+    });
+    let mut state = directory_store::load(&dir_config.state_file)?;
+    let now = directory_protocol::unix_now();
+    let saved = state.nodes.len();
+    state.nodes.retain(|_, entry| !entry.is_expired(now));
+    for entry in state.nodes.values_mut() {
+        entry.reachable = true;
+    }
+    println!("[DIR] Restored {} of {} saved nodes from {}.", state.nodes.len(), saved, dir_config.state_file);
+
+    let nodes: NodeList = Arc::new(Mutex::new(Registry { state, state_file: dir_config.state_file.clone() }));
+    tokio::spawn(expire_nodes(nodes.clone(), dir_config.revocation_file.clone()));
+    if dir_config.probe_nodes {
+        println!("[DIR] Actively probing registered nodes for reachability.");
+        tokio::spawn(probe_nodes(nodes.clone()));
+    }
+
     let listener = TcpListener::bind(listen_addr).await?;
     println!("[DIR] Listening for secure TLS connections...");
 
@@ -49,66 +152,353 @@ pub async fn run(listen_addr: &str, master_secret: &str, cert_path: &str, key_pa
         let (stream, addr) = listener.accept().await?;
         let acceptor_clone = acceptor.clone();
         let nodes_clone = nodes.clone();
-        let secret_clone = secret.clone();
+        let authority_clone = authority.clone();
 
         tokio::spawn(async move {
-            match acceptor_clone.accept(stream).await {
-                Ok(tls_stream) => {
+            match tokio::time::timeout(protocol::HANDSHAKE_TIMEOUT, acceptor_clone.accept(stream)).await {
+                Ok(Ok(tls_stream)) => {
                     println!("[DIR] Accepted secure connection from {}", addr);
-                    if let Err(e) = handle_connection(tls_stream, nodes_clone, secret_clone).await {
+                    if let Err(e) = handle_connection(tls_stream, nodes_clone, authority_clone).await {
                         eprintln!("[DIR] Error handling connection from {}: {}", addr, e);
                     }
                 }
-                Err(e) => {
+                Ok(Err(e)) => {
                     eprintln!("[DIR] TLS handshake error with {}: {}", addr, e);
                 }
+                Err(_) => {
+                    eprintln!("[DIR] TLS handshake with {} timed out", addr);
+                }
             }
         });
     }
 }
 
-async fn handle_connection<S>(mut stream: S, nodes: NodeList, master_secret: String) -> Result<(), Box<dyn Error>>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send,
-{
-    let msg_len = stream.read_u32().await?;
-    let mut msg_buf = vec![0; msg_len as usize];
-    stream.read_exact(&mut msg_buf).await?;
+async fn handle_connection(mut stream: TlsStream<TcpStream>, nodes: NodeList, authority: Arc<Authority>) -> Result<(), Box<dyn Error>> {
+    // The client certificate was issued for the member key, so the key names the member.
+    let peer_member = match stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) {
+        Some(cert) => Some(directory_protocol::member_id(&tls_setup::certificate_public_key(&cert.0)?)),
+        None => None,
+    };
+    // Clients from before protocol versioning start with their request instead of a hello.
+    // It is still answered in the format they understand, so they can show why. The first
+    // frame may therefore be as large as a request, though a hello inside it may not.
+    let hello = protocol::read_handshake_frame(&mut stream, MessageKind::DirectoryRequest).await?;
+    let Some(support) = ProtocolSupport::decode_hello(&hello) else {
+        return deny(&mut stream, "a request", "this directory needs a newer GiralNet version; please upgrade".to_string()).await;
+    };
+    protocol::write_hello(&mut stream).await?;
+    if let Err(reason) = support.negotiate() {
+        eprintln!("[DIR] Refused a client speaking protocol {}: {}", support, reason);
+        return Ok(());
+    }
 
-    let request: DirectoryRequest = bincode::deserialize(&msg_buf)?;
+    let msg_buf = protocol::read_handshake_frame(&mut stream, MessageKind::DirectoryRequest).await?;
+    let request: DirectoryRequest = protocol::decode(&msg_buf, MessageKind::DirectoryRequest)?;
+    let revoked = directory_store::load_revocations(&authority.revocation_file)?;
 
     match request {
-        DirectoryRequest::Register { info, secret } => {
-            if secret != master_secret {
-                eprintln!("[DIR] Denied registration from {} due to invalid secret.", info.address);
-                return Ok(());
-            }
-            println!("[DIR] Received registration from node at {}", info.address);
+        DirectoryRequest::Enroll { invite, public_key, signature } => {
+            let member_id = directory_protocol::member_id(&public_key);
+            let mut nodes_lock = nodes.lock().await;
+            let redeemed = redeem_invite(&nodes_lock, &authority.secret, &invite, &public_key, &signature)
+                .and_then(|invite| if revoked.contains(&member_id) { Err("this member key has been revoked".into()) } else { Ok(invite) })
+                .and_then(|invite| Ok((authority.team_ca.issue_member_cert(&member_id, &invite.name, &public_key)?, invite)))
+                .map_err(|e| e.to_string());
+            let (certificate, invite) = match redeemed {
+                Ok(redeemed) => redeemed,
+                Err(reason) => {
+                    drop(nodes_lock);
+                    return deny(&mut stream, "enrollment", reason).await;
+                }
+            };
+            nodes_lock.state.redeemed_invites.insert(invite.nonce, invite.expires);
+            nodes_lock.state.members.insert(member_id.clone(), Member {
+                name: invite.name.clone(),
+                public_key,
+                enrolled: directory_protocol::unix_now(),
+            });
+            nodes_lock.persist();
+            drop(nodes_lock);
+            println!("[DIR] Enrolled member '{}' as {}.", invite.name, member_id);
+            send_response(&mut stream, &DirectoryResponse::Enrolled { member_id, certificate }).await?;
+        }
+        DirectoryRequest::Register { descriptor, auth } => {
+            let address = descriptor.info.address;
             let mut nodes_lock = nodes.lock().await;
-            nodes_lock.insert(info.address, info);
-            let ack = bincode::serialize(&DirectoryResponse::Ack)?;
-            stream.write_u32(ack.len() as u32).await?;
-            stream.write_all(&ack).await?;
-            println!("[DIR] Node registered. Total nodes: {}", nodes_lock.len());
+            let registered = authenticate(&nodes_lock, &revoked, peer_member.as_deref(), &auth, &descriptor.signature)
+                .and_then(|_| nodes_lock.register(*descriptor, auth.member_id))
+                .map_err(|e| e.to_string());
+            let total = nodes_lock.state.nodes.len();
+            drop(nodes_lock);
+            if let Err(reason) = registered {
+                return deny(&mut stream, &format!("registration from {}", address), reason).await;
+            }
+            println!("[DIR] Received registration from node at {}", address);
+            send_response(&mut stream, &DirectoryResponse::Ack).await?;
+            println!("[DIR] Node registered. Total nodes: {}", total);
         }
-        DirectoryRequest::GetNodes { secret } => {
-            if secret != master_secret {
-                eprintln!("[DIR] Denied node list request due to invalid secret.");
+        DirectoryRequest::Heartbeat { address, timestamp, signature } => {
+            let mut nodes_lock = nodes.lock().await;
+            let Some(entry) = nodes_lock.state.nodes.get_mut(&address) else {
+                println!("[DIR] Heartbeat from unregistered node {}, asking it to register.", address);
+                drop(nodes_lock);
+                send_response(&mut stream, &DirectoryResponse::UnknownNode).await?;
                 return Ok(());
+            };
+            let checked = check_heartbeat(entry, timestamp, &signature).map_err(|e| e.to_string());
+            if let Err(reason) = checked {
+                drop(nodes_lock);
+                return deny(&mut stream, &format!("heartbeat from {}", address), reason).await;
             }
-            println!("[DIR] Received request for node list.");
+            if peer_member.as_deref() != Some(entry.member_id.as_str()) {
+                drop(nodes_lock);
+                return deny(&mut stream, &format!("heartbeat from {}", address), "client certificate does not belong to the node's member".into()).await;
+            }
+            if revoked.contains(&entry.member_id) {
+                nodes_lock.state.nodes.remove(&address);
+                nodes_lock.persist();
+                drop(nodes_lock);
+                return deny(&mut stream, &format!("heartbeat from {}", address), "the node's member has been revoked".into()).await;
+            }
+            entry.last_seen = directory_protocol::unix_now();
+            entry.last_heartbeat = timestamp;
+            drop(nodes_lock);
+            send_response(&mut stream, &DirectoryResponse::Ack).await?;
+        }
+        DirectoryRequest::GetNodes { auth } => {
             let nodes_lock = nodes.lock().await;
-            let node_list: Vec<NodeInfo> = nodes_lock.values().cloned().collect();
-            let response = DirectoryResponse::NodeList(node_list);
-            let res_bytes = bincode::serialize(&response)?;
-            stream.write_u32(res_bytes.len() as u32).await?;
-            stream.write_all(&res_bytes).await?;
-            println!("[DIR] Sent list of {} nodes to proxy.", nodes_lock.len());
+            let checked = authenticate(&nodes_lock, &revoked, peer_member.as_deref(), &auth, GET_NODES_CONTEXT).map_err(|e| e.to_string());
+            if let Err(reason) = checked {
+                drop(nodes_lock);
+                return deny(&mut stream, "node list request", reason).await;
+            }
+            println!("[DIR] Received request for node list.");
+            let now = directory_protocol::unix_now();
+            let live_nodes: Vec<ConsensusEntry> = nodes_lock.state.nodes.values()
+                .filter(|entry| entry.is_live(now) && !revoked.contains(&entry.member_id))
+                .map(|entry| ConsensusEntry {
+                    descriptor: entry.descriptor.clone(),
+                    operator: entry.member_id.clone(),
+                })
+                .collect();
+            drop(nodes_lock);
+            let node_count = live_nodes.len();
+            let consensus = Consensus {
+                valid_after: now,
+                fresh_until: now + CONSENSUS_FRESH_SECS,
+                valid_until: now + CONSENSUS_VALID_SECS,
+                nodes: live_nodes,
+            };
+            let response = DirectoryResponse::Consensus(SignedConsensus::sign(consensus, &authority.signing_key)?);
+            send_response(&mut stream, &response).await?;
+            println!("[DIR] Sent signed consensus with {} live nodes to proxy.", node_count);
         }
     }
     Ok(())
 }
 
+async fn deny<S: AsyncWrite + Unpin>(stream: &mut S, what: &str, reason: String) -> Result<(), Box<dyn Error>> {
+    eprintln!("[DIR] Denied {}: {}", what, reason);
+    send_response(stream, &DirectoryResponse::Denied(reason)).await
+}
+
+/// Checks that `auth` was made by an enrolled member that hasn't been revoked, over a TLS
+/// connection authenticated with that member's client certificate.
+fn authenticate(registry: &Registry, revoked: &HashSet<String>, peer_member: Option<&str>, auth: &MemberAuth, context: &[u8]) -> Result<(), Box<dyn Error>> {
+    match peer_member {
+        None => return Err("no client certificate was presented".into()),
+        Some(peer_member) if peer_member != auth.member_id => return Err("client certificate belongs to a different member".into()),
+        Some(_) => {}
+    }
+    if revoked.contains(&auth.member_id) {
+        return Err("this member has been revoked".into());
+    }
+    let member = registry.state.members.get(&auth.member_id).ok_or("not an enrolled member")?;
+    auth.verify(&member.public_key, context, directory_protocol::unix_now())
+}
+
+/// A one-time enrollment invite. The token handed to the member is the hex-encoded invite
+/// followed by an HMAC under the directory secret, so the directory doesn't have to keep
+/// track of invites until they are redeemed.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Invite {
+    nonce: [u8; 16],
+    name: String,
+    expires: u64,
+}
+
+/// Issues an invite token for a new member called `name`.
+pub fn create_invite(dir_config: &DirectoryConfig, name: &str) -> Result<String, Box<dyn Error>> {
+    let secret = check_secret(&dir_config.secret)?;
+    let invite = Invite {
+        nonce: crypto::random_bytes(),
+        name: name.to_string(),
+        expires: directory_protocol::unix_now() + INVITE_VALID_SECS,
+    };
+    let body = bincode::serialize(&invite)?;
+    let tag = crypto::hmac_sha256(secret.as_bytes(), &body);
+    Ok(format!("{}.{}", crypto::to_hex(&body), crypto::to_hex(&tag)))
+}
+
+/// Adds a member to the revocation list. A running directory refuses them from their next request on.
+pub fn revoke_member(dir_config: &DirectoryConfig, member_id: &str) -> Result<(), Box<dyn Error>> {
+    let member_id = member_id.trim().to_ascii_lowercase();
+    if member_id.len() != 64 || crypto::from_hex(&member_id).is_err() {
+        return Err(format!("'{}' is not a member ID", member_id).into());
+    }
+    directory_store::revoke(&dir_config.revocation_file, &member_id)
+}
+
+/// Prints the enrolled members recorded in the directory's state file.
+pub fn list_members(dir_config: &DirectoryConfig) -> Result<(), Box<dyn Error>> {
+    let state = directory_store::load(&dir_config.state_file)?;
+    let revoked = directory_store::load_revocations(&dir_config.revocation_file)?;
+    if state.members.is_empty() {
+        println!("No members have enrolled yet.");
+    }
+    for (member_id, member) in &state.members {
+        let nodes = state.nodes.values().filter(|entry| &entry.member_id == member_id).count();
+        let status = if revoked.contains(member_id) { " (revoked)" } else { "" };
+        println!("{}  {}  {} node(s){}", member_id, member.name, nodes, status);
+    }
+    Ok(())
+}
+
+/// The directory secret keys invite tokens, so it must not be trivially guessable.
+fn check_secret(secret: &str) -> Result<&str, Box<dyn Error>> {
+    if secret.len() < 8 {
+        return Err("The directory secret must be at least 8 characters long".into());
+    }
+    Ok(secret)
+}
+
+fn redeem_invite(registry: &Registry, secret: &str, token: &str, public_key: &RsaPublicKey, signature: &[u8]) -> Result<Invite, Box<dyn Error>> {
+    crypto::verify(public_key, &directory_protocol::enroll_payload(token)?, signature)
+        .map_err(|_| "enrollment is not signed by the key being enrolled")?;
+    let (body, tag) = token.trim().split_once('.').ok_or("malformed invite")?;
+    let body = crypto::from_hex(body)?;
+    if !crypto::hmac_sha256_verify(secret.as_bytes(), &body, &crypto::from_hex(tag)?) {
+        return Err("invite was not issued by this directory".into());
+    }
+    let invite: Invite = protocol::decode(&body, MessageKind::Invite)?;
+    if invite.expires < directory_protocol::unix_now() {
+        return Err("invite has expired".into());
+    }
+    if registry.state.redeemed_invites.contains_key(&invite.nonce) {
+        return Err("invite has already been used".into());
+    }
+    Ok(invite)
+}
+
+async fn send_response<S: AsyncWrite + Unpin>(stream: &mut S, response: &DirectoryResponse) -> Result<(), Box<dyn Error>> {
+    let res_bytes = bincode::serialize(response)?;
+    protocol::write_frame(stream, &res_bytes).await?;
+    Ok(())
+}
+
+fn check_heartbeat(entry: &NodeEntry, timestamp: u64, signature: &[u8]) -> Result<(), Box<dyn Error>> {
+    let info = &entry.descriptor.info;
+    let payload = directory_protocol::heartbeat_payload(info.address, timestamp)?;
+    crypto::verify(&info.public_key, &payload, signature).map_err(|_| "heartbeat signature does not match the registered identity key")?;
+    if timestamp <= entry.last_heartbeat {
+        return Err("heartbeat is not newer than the last one".into());
+    }
+    if timestamp > directory_protocol::unix_now() + MAX_CLOCK_SKEW_SECS {
+        return Err("heartbeat is timestamped in the future".into());
+    }
+    Ok(())
+}
+
+/// Periodically drops nodes that stopped sending heartbeats, members that were revoked
+/// together with their nodes, and invites that can no longer be redeemed, then saves the
+/// registry, which also records the heartbeats received since the last save.
+async fn expire_nodes(nodes: NodeList, revocation_file: String) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = directory_protocol::unix_now();
+        let revoked = directory_store::load_revocations(&revocation_file).unwrap_or_else(|e| {
+            eprintln!("[DIR] {}", e);
+            HashSet::new()
+        });
+        let mut nodes_lock = nodes.lock().await;
+        let state = &mut nodes_lock.state;
+        state.members.retain(|member_id, member| {
+            let is_revoked = revoked.contains(member_id);
+            if is_revoked {
+                println!("[DIR] Member '{}' ({}) has been revoked and was removed.", member.name, member_id);
+            }
+            !is_revoked
+        });
+        let members = &state.members;
+        state.nodes.retain(|address, entry| {
+            if !members.contains_key(&entry.member_id) {
+                println!("[DIR] Node {} belongs to a revoked member and was removed.", address);
+                return false;
+            }
+            let expired = entry.is_expired(now);
+            if expired {
+                println!("[DIR] Node {} missed its heartbeats and was removed.", address);
+            }
+            !expired
+        });
+        state.redeemed_invites.retain(|_, expires| *expires >= now);
+        nodes_lock.persist();
+    }
+}
+
+/// Periodically opens a TCP connection to every registered node and hides the ones that
+/// can't be reached from the consensus until a later probe succeeds.
+async fn probe_nodes(nodes: NodeList) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let addresses: Vec<SocketAddr> = nodes.lock().await.state.nodes.keys().copied().collect();
+
+        let mut probes = JoinSet::new();
+        for address in addresses {
+            probes.spawn(async move {
+                let reachable = matches!(tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(address)).await, Ok(Ok(_)));
+                (address, reachable)
+            });
+        }
+
+        while let Some(Ok((address, reachable))) = probes.join_next().await {
+            if let Some(entry) = nodes.lock().await.state.nodes.get_mut(&address) {
+                if entry.reachable != reachable {
+                    println!("[DIR] Node {} is now {}.", address, if reachable { "reachable" } else { "unreachable" });
+                }
+                entry.reachable = reachable;
+            }
+        }
+    }
+}
+
+fn check_descriptor(descriptor: &NodeDescriptor) -> Result<(), Box<dyn Error>> {
+    descriptor.verify().map_err(|_| "descriptor signature does not match its identity key")?;
+    let now = directory_protocol::unix_now();
+    let published = descriptor.info.published;
+    if published > now + MAX_CLOCK_SKEW_SECS {
+        return Err("descriptor is published in the future".into());
+    }
+    if published + DESCRIPTOR_MAX_AGE_SECS < now {
+        return Err("descriptor is too old".into());
+    }
+    Ok(())
+}
+
+/// Refuses a registration that would take over the entry of another member's node at the
+/// same address. The member that owns the entry may replace it, including with a new
+/// identity key after the node rotated its key. An entry that has expired no longer belongs
+/// to anyone.
+fn check_owner(existing: Option<&NodeEntry>, member_id: &str) -> Result<(), Box<dyn Error>> {
+    let now = directory_protocol::unix_now();
+    match existing.filter(|entry| !entry.is_expired(now)) {
+        Some(existing) if existing.member_id != member_id => Err("the address is registered to another member".into()),
+        _ => Ok(()),
+    }
+}
+
 fn load_certs_and_key(cert_path: &str, key_path: &str) -> Result<(Vec<Certificate>, PrivateKey), Box<dyn Error>> {
     let mut cert_reader = BufReader::new(File::open(cert_path)?);
     let certs = certs(&mut cert_reader)?
@@ -123,4 +513,60 @@ fn load_certs_and_key(cert_path: &str, key_path: &str) -> Result<(Vec<Certificat
         return Err("Expected a single private key".into());
     }
     Ok((certs, PrivateKey(keys.remove(0))))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory_protocol::NodeInfo;
+    use crate::exit_policy::ExitPolicy;
+
+    fn registry(name: &str) -> Registry {
+        let path = std::env::temp_dir().join(format!("giralnet-{}-{}.bin", name, std::process::id()));
+        Registry { state: DirectoryState::default(), state_file: path.to_string_lossy().into_owned() }
+    }
+
+    fn descriptor(address: SocketAddr, identity: &RsaPrivateKey) -> NodeDescriptor {
+        let info = NodeInfo {
+            address,
+            public_key: identity.to_public_key(),
+            onion_key: Some([0; 32]),
+            bandwidth: 0,
+            published: directory_protocol::unix_now(),
+            exit_policy: ExitPolicy::reject_all(),
+            flags: Vec::new(),
+            protocol: ProtocolSupport::ours(),
+        };
+        NodeDescriptor::sign(info, identity).unwrap()
+    }
+
+    fn key() -> RsaPrivateKey {
+        RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap()
+    }
+
+    #[test]
+    fn rotated_key_replaces_the_members_entry() {
+        let mut registry = registry("rotation");
+        let address: SocketAddr = "192.0.2.7:9001".parse().unwrap();
+        let (old_key, new_key) = (key(), key());
+        registry.register(descriptor(address, &old_key), "m1".to_string()).unwrap();
+        registry.register(descriptor(address, &new_key), "m1".to_string()).unwrap();
+        let _ = std::fs::remove_file(&registry.state_file);
+        assert_eq!(registry.state.nodes.len(), 1);
+        assert_eq!(registry.state.nodes[&address].descriptor.info.public_key, new_key.to_public_key());
+    }
+
+    #[test]
+    fn another_member_cannot_take_over_a_live_entry() {
+        let mut registry = registry("takeover");
+        let address: SocketAddr = "192.0.2.7:9001".parse().unwrap();
+        let owner_key = key();
+        registry.register(descriptor(address, &owner_key), "m1".to_string()).unwrap();
+        assert!(registry.register(descriptor(address, &key()), "m2".to_string()).is_err());
+
+        // Once the entry expires the address is free again.
+        registry.state.nodes.get_mut(&address).unwrap().last_seen -= NODE_EXPIRY_SECS + 1;
+        registry.register(descriptor(address, &key()), "m2".to_string()).unwrap();
+        let _ = std::fs::remove_file(&registry.state_file);
+        assert_eq!(registry.state.nodes[&address].member_id, "m2");
+    }
+}
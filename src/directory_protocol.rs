@@ -14,12 +14,24 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use rsa::RsaPublicKey;
+use crate::{crypto, protocol, tls_client};
+use crate::exit_policy::ExitPolicy;
+use crate::protocol::{MessageKind, ProtocolSupport};
+use crate::tls_client::ClientIdentity;
+use rsa::{RsaPrivateKey, RsaPublicKey};
 use rsa::pkcs8::{EncodePublicKey, DecodePublicKey, LineEnding};
 use serde::{Serialize, Deserialize};
+use std::error::Error;
+use std::fmt;
 use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-mod serde_rsa_public_key {
+/// How far a descriptor's or consensus's timestamps may run ahead of our clock.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 10 * 60;
+/// How often registered nodes prove to the directory that they are still running.
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 60;
+
+pub mod serde_rsa_public_key {
     use super::*;
     use serde::{Serializer, Deserializer};
 
@@ -46,22 +58,246 @@ pub struct NodeInfo {
     pub address: SocketAddr,
     #[serde(with = "serde_rsa_public_key")]
     pub public_key: RsaPublicKey,
+    /// X25519 key used for ntor handshakes. Nodes that don't publish one can't be used in circuits.
+    pub onion_key: Option<[u8; 32]>,
+    /// Advertised bandwidth in bytes per second, 0 if the operator didn't declare one.
+    pub bandwidth: u64,
+    /// Unix time at which the node produced this descriptor.
+    pub published: u64,
+    /// Destinations the node connects to when it is the exit of a circuit.
+    pub exit_policy: ExitPolicy,
+    /// Roles the node offers and labels its operator gave it.
+    pub flags: Vec<NodeFlag>,
+    /// Protocol versions and features the node speaks, so proxies can leave out nodes
+    /// they cannot build circuits through.
+    pub protocol: ProtocolSupport,
+}
+
+impl NodeInfo {
+    pub fn has_flag(&self, flag: &NodeFlag) -> bool {
+        self.flags.contains(flag)
+    }
+}
+
+/// A role a node offers in circuits, or a free-form label assigned by its operator. Nodes
+/// with neither `Guard` nor `Exit` are relay-only and only used as middle hops.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum NodeFlag {
+    /// May be the first hop, which sees the user's address.
+    Guard,
+    /// May be the last hop, which connects to destinations.
+    Exit,
+    Other(String),
+}
+
+impl NodeFlag {
+    pub fn parse(flag: &str) -> Self {
+        match flag.to_ascii_lowercase().as_str() {
+            "guard" => NodeFlag::Guard,
+            "exit" => NodeFlag::Exit,
+            _ => NodeFlag::Other(flag.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for NodeFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeFlag::Guard => f.write_str("Guard"),
+            NodeFlag::Exit => f.write_str("Exit"),
+            NodeFlag::Other(label) => f.write_str(label),
+        }
+    }
+}
+
+/// A [`NodeInfo`] signed with the node's own identity key, binding its address and onion
+/// key to that identity.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeDescriptor {
+    pub info: NodeInfo,
+    pub signature: Vec<u8>,
+}
+
+impl NodeDescriptor {
+    pub fn sign(info: NodeInfo, identity: &RsaPrivateKey) -> Result<Self, Box<dyn Error>> {
+        let signature = crypto::sign(identity, &bincode::serialize(&info)?);
+        Ok(NodeDescriptor { info, signature })
+    }
+
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        crypto::verify(&self.info.public_key, &bincode::serialize(&self.info)?, &self.signature)
+    }
+}
+
+/// The directory's view of the network for a bounded period of time.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Consensus {
+    pub valid_after: u64,
+    /// After this time clients should fetch a newer consensus, but may keep using this one.
+    pub fresh_until: u64,
+    pub valid_until: u64,
+    pub nodes: Vec<ConsensusEntry>,
+}
+
+/// A node as the consensus lists it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConsensusEntry {
+    pub descriptor: NodeDescriptor,
+    /// Member ID that registered the node, as authenticated by the directory. Nodes
+    /// sharing an operator are never used in the same circuit.
+    pub operator: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignedConsensus {
+    pub consensus: Consensus,
+    pub signature: Vec<u8>,
+}
+
+impl SignedConsensus {
+    pub fn sign(consensus: Consensus, signing_key: &RsaPrivateKey) -> Result<Self, Box<dyn Error>> {
+        let signature = crypto::sign(signing_key, &bincode::serialize(&consensus)?);
+        Ok(SignedConsensus { consensus, signature })
+    }
+
+    /// Checks the signature against the pinned directory key and that the consensus is
+    /// currently valid, returning it on success.
+    pub fn verify(self, directory_key: &RsaPublicKey, now: u64) -> Result<Consensus, Box<dyn Error>> {
+        crypto::verify(directory_key, &bincode::serialize(&self.consensus)?, &self.signature)
+            .map_err(|_| "Consensus is not signed by the pinned directory key")?;
+        if now + MAX_CLOCK_SKEW_SECS < self.consensus.valid_after {
+            return Err("Consensus is not valid yet. Is the system clock correct?".into());
+        }
+        if now > self.consensus.valid_until {
+            return Err("Consensus has expired".into());
+        }
+        Ok(self.consensus)
+    }
+}
+
+/// Proves that a directory request comes from an enrolled member, by signing it with the
+/// member key that was enrolled.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemberAuth {
+    pub member_id: String,
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+impl MemberAuth {
+    /// Signs for a request whose content is summarized by `context`, so the signature can't
+    /// be lifted onto a different request.
+    pub fn sign(member_key: &RsaPrivateKey, context: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let member_id = member_id(&member_key.to_public_key());
+        let timestamp = unix_now();
+        let signature = crypto::sign(member_key, &member_auth_payload(&member_id, timestamp, context)?);
+        Ok(MemberAuth { member_id, timestamp, signature })
+    }
+
+    pub fn verify(&self, member_key: &RsaPublicKey, context: &[u8], now: u64) -> Result<(), Box<dyn Error>> {
+        let payload = member_auth_payload(&self.member_id, self.timestamp, context)?;
+        crypto::verify(member_key, &payload, &self.signature).map_err(|_| "request is not signed by the member key")?;
+        if self.timestamp.abs_diff(now) > MAX_CLOCK_SKEW_SECS {
+            return Err("request timestamp is too far from the directory's clock".into());
+        }
+        Ok(())
+    }
+}
+
+fn member_auth_payload(member_id: &str, timestamp: u64, context: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(bincode::serialize(&("giralnet-member", member_id, timestamp, context))?)
+}
+
+/// Identifier the directory knows a member by: the hex fingerprint of their member key.
+pub fn member_id(member_key: &RsaPublicKey) -> String {
+    crypto::to_hex(&crypto::node_id(member_key))
+}
+
+/// Bytes a member signs when enrolling, proving they hold the key being enrolled.
+pub fn enroll_payload(invite: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(bincode::serialize(&("giralnet-enroll", invite))?)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DirectoryRequest {
+    /// Redeems a one-time invite issued by the directory operator, enrolling `public_key`
+    /// as a member key. `signature` is made with that key over [`enroll_payload`].
+    Enroll {
+        invite: String,
+        #[serde(with = "serde_rsa_public_key")]
+        public_key: RsaPublicKey,
+        signature: Vec<u8>,
+    },
+
+    /// `auth` signs over the descriptor's signature.
     Register {
-        info: NodeInfo,
-        secret: String,
+        descriptor: Box<NodeDescriptor>,
+        auth: MemberAuth,
     },
 
+    /// Periodic proof of life from a registered node, signed with its identity key.
+    Heartbeat {
+        address: SocketAddr,
+        timestamp: u64,
+        signature: Vec<u8>,
+    },
+
+    /// `auth` signs over [`GET_NODES_CONTEXT`].
     GetNodes {
-        secret: String,
+        auth: MemberAuth,
     },
 }
 
+pub const GET_NODES_CONTEXT: &[u8] = b"get-nodes";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DirectoryResponse {
     Ack,
-    NodeList(Vec<NodeInfo>),
+    /// The directory doesn't know the node (it expired or the directory restarted); it should register again.
+    UnknownNode,
+    Consensus(SignedConsensus),
+    /// `certificate` is the member's TLS client certificate, issued by the team CA.
+    Enrolled {
+        member_id: String,
+        certificate: String,
+    },
+    /// The request was refused, with the reason.
+    Denied(String),
+}
+
+/// Bytes a node signs for a heartbeat. The tag keeps heartbeat signatures from ever being
+/// mistaken for descriptor signatures made with the same key.
+pub fn heartbeat_payload(address: SocketAddr, timestamp: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(bincode::serialize(&("giralnet-heartbeat", address, timestamp))?)
+}
+
+/// Sends a single request to the directory over TLS and waits for its response. Every
+/// request but [`DirectoryRequest::Enroll`] needs the member's client certificate.
+pub async fn send_request(dir_addr: &str, ca_cert_path: &str, identity: Option<&ClientIdentity>, request: &DirectoryRequest) -> Result<DirectoryResponse, Box<dyn Error>> {
+    let mut stream = tls_client::connect(dir_addr, ca_cert_path, identity).await?;
+    protocol::client_hello(&mut stream, "the directory").await?;
+    let req_bytes = bincode::serialize(request)?;
+    protocol::write_frame(&mut stream, &req_bytes).await?;
+
+    let res_buf = protocol::read_frame(&mut stream, MessageKind::DirectoryResponse).await?;
+    protocol::decode(&res_buf, MessageKind::DirectoryResponse)
+}
+
+/// Enrolls `member_key` with the directory using a one-time invite, returning the member ID
+/// and the client certificate issued for the key.
+pub async fn enroll(dir_addr: &str, ca_cert_path: &str, member_key: &RsaPrivateKey, invite: &str) -> Result<(String, String), Box<dyn Error>> {
+    let request = DirectoryRequest::Enroll {
+        invite: invite.to_string(),
+        public_key: member_key.to_public_key(),
+        signature: crypto::sign(member_key, &enroll_payload(invite)?),
+    };
+    match send_request(dir_addr, ca_cert_path, None, &request).await? {
+        DirectoryResponse::Enrolled { member_id, certificate } => Ok((member_id, certificate)),
+        DirectoryResponse::Denied(reason) => Err(format!("The directory refused the enrollment: {}", reason).into()),
+        _ => Err("Unexpected response from the directory".into()),
+    }
 }
\ No newline at end of file
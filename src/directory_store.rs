@@ -0,0 +1,145 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+use crate::crypto;
+use crate::directory_protocol::{serde_rsa_public_key, NodeDescriptor};
+use rsa::RsaPublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Bumped whenever the layout of the state file changes, so a file in another format is
+/// refused instead of being misread.
+const STATE_VERSION: u32 = 1;
+
+/// A registered node as the directory tracks it.
+#[derive(Serialize, Deserialize)]
+pub struct NodeEntry {
+    pub descriptor: NodeDescriptor,
+    /// Member that registered the node. Revoking the member removes the node.
+    pub member_id: String,
+    /// Unix time of the last registration or accepted heartbeat.
+    pub last_seen: u64,
+    /// Timestamp of the newest accepted heartbeat, so old ones can't be replayed.
+    pub last_heartbeat: u64,
+    /// Cleared when an active probe fails to connect, set again once one succeeds.
+    pub reachable: bool,
+}
+
+/// A participant that redeemed an invite, keyed by member ID.
+#[derive(Serialize, Deserialize)]
+pub struct Member {
+    /// Name the operator gave the invite, to tell members apart.
+    pub name: String,
+    #[serde(with = "serde_rsa_public_key")]
+    pub public_key: RsaPublicKey,
+    pub enrolled: u64,
+}
+
+/// Everything the directory keeps across restarts.
+#[derive(Serialize, Deserialize, Default)]
+pub struct DirectoryState {
+    pub nodes: HashMap<SocketAddr, NodeEntry>,
+    pub members: HashMap<String, Member>,
+    /// Nonces of invites that were already used, with the time the invite would have expired.
+    pub redeemed_invites: HashMap<[u8; 16], u64>,
+}
+
+/// Loads the state saved at `file_path`, or an empty state if there is none yet.
+pub fn load(file_path: &str) -> Result<DirectoryState, Box<dyn Error>> {
+    if !Path::new(file_path).exists() {
+        return Ok(DirectoryState::default());
+    }
+    let bytes = fs::read(file_path)?;
+    let decode_error = |e: bincode::Error| format!("Failed to decode directory state in {}: {}", file_path, e);
+    let version: u32 = bincode::deserialize(&bytes).map_err(decode_error)?;
+    if version != STATE_VERSION {
+        return Err(format!("Directory state in {} has unsupported version {}", file_path, version).into());
+    }
+    let (_, state): (u32, DirectoryState) = bincode::deserialize(&bytes).map_err(decode_error)?;
+    Ok(state)
+}
+
+/// Replaces the state file atomically, so a crash mid-write leaves the previous state intact.
+pub fn save(file_path: &str, state: &DirectoryState) -> Result<(), Box<dyn Error>> {
+    let bytes = bincode::serialize(&(STATE_VERSION, state))?;
+    crypto::write_private_file(file_path, &bytes)
+}
+
+/// Reads the revocation list: one member ID per line, blank lines and `#` comments ignored.
+/// The operator may edit the file by hand; the directory rereads it on every request.
+pub fn load_revocations(file_path: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    if !Path::new(file_path).exists() {
+        return Ok(HashSet::new());
+    }
+    let contents = fs::read_to_string(file_path)
+        .map_err(|e| format!("Failed to read revocation list {}: {}", file_path, e))?;
+    Ok(contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Appends `member_id` to the revocation list.
+pub fn revoke(file_path: &str, member_id: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(file_path)?;
+    writeln!(file, "{}", member_id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::RsaPrivateKey;
+
+    fn state_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("giralnet-{}-{}.bin", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn member() -> Member {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        Member { name: "alice".to_string(), public_key: key.to_public_key(), enrolled: 1 }
+    }
+
+    #[test]
+    fn saved_state_loads_back() {
+        let file = state_file("roundtrip");
+        let mut state = DirectoryState::default();
+        state.members.insert("m1".to_string(), member());
+        state.redeemed_invites.insert([1; 16], 99);
+        save(&file, &state).unwrap();
+        let loaded = load(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(loaded.members["m1"].name, "alice");
+        assert_eq!(loaded.redeemed_invites[&[1; 16]], 99);
+    }
+
+    #[test]
+    fn state_in_another_format_is_refused() {
+        let file = state_file("unknown-version");
+        fs::write(&file, bincode::serialize(&(STATE_VERSION + 1, HashMap::<SocketAddr, u8>::new())).unwrap()).unwrap();
+        let loaded = load(&file);
+        fs::remove_file(&file).unwrap();
+        assert!(loaded.is_err());
+    }
+}
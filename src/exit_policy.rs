@@ -0,0 +1,338 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Exit policies: which destinations an exit node is willing to connect to. A policy is an
+//! ordered list of rules like `reject 10.0.0.0/8:*` or `accept *:80-443`; the first rule
+//! matching a destination decides, and destinations no rule matches are rejected.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::protocol::StreamTarget;
+
+/// Ranges no exit should reach on behalf of others unless its operator says so: this
+/// host, the operator's LAN, and other non-routable space.
+const PRIVATE_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    Accept,
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExitRule {
+    pub action: ExitAction,
+    /// Network address and prefix length, `None` for any address.
+    pub network: Option<(IpAddr, u8)>,
+    pub ports: (u16, u16),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExitPolicy {
+    pub rules: Vec<ExitRule>,
+}
+
+impl ExitRule {
+    /// Parses `accept|reject ADDRESS[/BITS]:PORT[-PORT]`, where ADDRESS and PORT may be `*`
+    /// and IPv6 addresses are written in brackets.
+    pub fn parse(rule: &str) -> Result<Self, Box<dyn Error>> {
+        let (action, pattern) = rule.trim().split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Exit policy rule '{}' needs an action and a pattern", rule))?;
+        let action = match action {
+            "accept" => ExitAction::Accept,
+            "reject" => ExitAction::Reject,
+            other => return Err(format!("Unknown exit policy action '{}' in '{}'", other, rule).into()),
+        };
+        let (address, ports) = pattern.trim().rsplit_once(':')
+            .ok_or_else(|| format!("Exit policy rule '{}' is missing a port", rule))?;
+        let network = match address {
+            "*" => None,
+            _ => Some(parse_network(address).map_err(|e| format!("{} in exit policy rule '{}'", e, rule))?),
+        };
+        let ports = match ports {
+            "*" => (1, u16::MAX),
+            _ => {
+                let (low, high) = ports.split_once('-').unwrap_or((ports, ports));
+                let low: u16 = low.parse().map_err(|_| format!("Invalid port in exit policy rule '{}'", rule))?;
+                let high: u16 = high.parse().map_err(|_| format!("Invalid port in exit policy rule '{}'", rule))?;
+                if low == 0 || low > high {
+                    return Err(format!("Invalid port range in exit policy rule '{}'", rule).into());
+                }
+                (low, high)
+            }
+        };
+        Ok(ExitRule { action, network, ports })
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        self.ports.0 <= port && port <= self.ports.1
+    }
+
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        let in_network = match self.network {
+            None => true,
+            Some((network, bits)) => in_network(addr.ip(), network, bits),
+        };
+        in_network && self.matches_port(addr.port())
+    }
+}
+
+impl fmt::Display for ExitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            ExitAction::Accept => "accept",
+            ExitAction::Reject => "reject",
+        };
+        let address = match self.network {
+            None => "*".to_string(),
+            Some((IpAddr::V4(ip), bits)) => format!("{}/{}", ip, bits),
+            Some((IpAddr::V6(ip), bits)) => format!("[{}]/{}", ip, bits),
+        };
+        let ports = match self.ports {
+            (1, u16::MAX) => "*".to_string(),
+            (low, high) if low == high => low.to_string(),
+            (low, high) => format!("{}-{}", low, high),
+        };
+        write!(f, "{} {}:{}", action, address, ports)
+    }
+}
+
+impl ExitPolicy {
+    /// Builds a node's policy from its configured rules, preceded by rejects for every
+    /// private range when `reject_private` is set.
+    pub fn from_config(rules: &[String], reject_private: bool) -> Result<Self, Box<dyn Error>> {
+        let mut policy = Vec::new();
+        if reject_private {
+            for network in PRIVATE_NETWORKS {
+                policy.push(ExitRule {
+                    action: ExitAction::Reject,
+                    network: Some(parse_network(network)?),
+                    ports: (1, u16::MAX),
+                });
+            }
+        }
+        for rule in rules {
+            policy.push(ExitRule::parse(rule)?);
+        }
+        Ok(ExitPolicy { rules: policy })
+    }
+
+    /// The policy of a node that is not an exit.
+    pub fn reject_all() -> Self {
+        ExitPolicy { rules: Vec::new() }
+    }
+
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        self.rules.iter()
+            .find(|rule| rule.matches(addr))
+            .is_some_and(|rule| rule.action == ExitAction::Accept)
+    }
+
+    /// Whether some public address on `port` may be allowed. Used to pick an exit for a
+    /// hostname, whose address only the exit learns.
+    pub fn allows_port(&self, port: u16) -> bool {
+        for rule in self.rules.iter().filter(|rule| rule.matches_port(port)) {
+            match (rule.action, rule.network) {
+                (ExitAction::Accept, _) => return true,
+                (ExitAction::Reject, None) => return false,
+                // Rejecting some networks still leaves the others.
+                (ExitAction::Reject, Some(_)) => {}
+            }
+        }
+        false
+    }
+
+    /// Whether `ip` is allowed on some port. Which rule decides only changes where a rule's
+    /// port range starts or ends, so the first port of every such stretch covers them all.
+    pub fn allows_address(&self, ip: IpAddr) -> bool {
+        let starts = self.rules.iter().flat_map(|rule| [Some(rule.ports.0), rule.ports.1.checked_add(1)]).flatten();
+        std::iter::once(1).chain(starts).any(|port| self.allows(&SocketAddr::new(ip, port)))
+    }
+
+    pub fn allows_target(&self, target: &StreamTarget) -> bool {
+        match target {
+            StreamTarget::Address(addr) => self.allows(addr),
+            StreamTarget::Domain(_, port) => self.allows_port(*port),
+        }
+    }
+}
+
+impl fmt::Display for ExitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<String> = self.rules.iter().map(ExitRule::to_string).collect();
+        f.write_str(&rules.join(", "))
+    }
+}
+
+fn parse_network(text: &str) -> Result<(IpAddr, u8), Box<dyn Error>> {
+    let (address, bits) = match text.split_once('/') {
+        Some((address, bits)) => (address, Some(bits)),
+        None => (text, None),
+    };
+    let address = address.strip_prefix('[').and_then(|a| a.strip_suffix(']')).unwrap_or(address);
+    let ip: IpAddr = address.parse().map_err(|_| format!("Invalid address '{}'", address))?;
+    let max_bits = if ip.is_ipv4() { 32 } else { 128 };
+    let bits = match bits {
+        Some(bits) => bits.parse().ok().filter(|b| *b <= max_bits).ok_or_else(|| format!("Invalid prefix length '{}'", bits))?,
+        None => max_bits,
+    };
+    Ok((ip, bits))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, bits: u8) -> bool {
+    // IPv4-mapped IPv6 destinations are matched as the IPv4 address they stand for.
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    };
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => prefix_matches(u32::from(ip) as u128, u32::from(network) as u128, bits, 32),
+        (IpAddr::V6(ip), IpAddr::V6(network)) => prefix_matches(u128::from(ip), u128::from(network), bits, 128),
+        _ => false,
+    }
+}
+
+fn prefix_matches(ip: u128, network: u128, bits: u8, width: u32) -> bool {
+    if bits == 0 {
+        return true;
+    }
+    let shift = width - bits as u32;
+    (ip >> shift) == (network >> shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: &[&str]) -> ExitPolicy {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        ExitPolicy::from_config(&rules, false).unwrap()
+    }
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn wildcards_match_every_address_and_port() {
+        let accept_all = policy(&["accept *:*"]);
+        assert!(accept_all.allows(&addr("192.0.2.7:1")));
+        assert!(accept_all.allows(&addr("[2001:db8::1]:65535")));
+        assert!(accept_all.allows_target(&StreamTarget::Domain("example.com".to_string(), 25)));
+
+        let web_anywhere = policy(&["accept *:443"]);
+        assert!(web_anywhere.allows(&addr("198.51.100.1:443")));
+        assert!(!web_anywhere.allows(&addr("198.51.100.1:80")));
+    }
+
+    #[test]
+    fn destinations_no_rule_matches_are_rejected() {
+        assert!(!ExitPolicy::reject_all().allows(&addr("192.0.2.7:80")));
+        assert!(!ExitPolicy::reject_all().allows_port(80));
+        assert!(!policy(&["accept 192.0.2.0/24:*"]).allows(&addr("198.51.100.1:80")));
+    }
+
+    #[test]
+    fn port_ranges_include_both_ends() {
+        let web = policy(&["accept *:80-443"]);
+        for port in [80, 200, 443] {
+            assert!(web.allows(&addr(&format!("192.0.2.7:{}", port))), "port {}", port);
+        }
+        for port in [79, 444] {
+            assert!(!web.allows(&addr(&format!("192.0.2.7:{}", port))), "port {}", port);
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let no_lan = policy(&["reject 10.0.0.0/8:*", "accept *:*"]);
+        assert!(!no_lan.allows(&addr("10.1.2.3:80")));
+        assert!(no_lan.allows(&addr("192.0.2.7:80")));
+
+        let lan_first = policy(&["accept *:*", "reject 10.0.0.0/8:*"]);
+        assert!(lan_first.allows(&addr("10.1.2.3:80")));
+
+        let no_smtp = policy(&["reject *:25", "accept *:*"]);
+        assert!(!no_smtp.allows(&addr("192.0.2.7:25")));
+        assert!(no_smtp.allows(&addr("192.0.2.7:26")));
+    }
+
+    #[test]
+    fn hostnames_are_judged_by_port() {
+        // Rejecting a network leaves public addresses on the port for the exit to reach.
+        let no_lan = policy(&["reject 10.0.0.0/8:*", "accept *:443"]);
+        assert!(no_lan.allows_target(&StreamTarget::Domain("example.com".to_string(), 443)));
+        assert!(!no_lan.allows_target(&StreamTarget::Domain("example.com".to_string(), 80)));
+        assert!(!policy(&["reject *:443", "accept *:*"]).allows_port(443));
+    }
+
+    #[test]
+    fn addresses_are_allowed_if_any_port_is() {
+        let rules = policy(&["reject *:1-442", "reject 10.0.0.0/8:*", "accept *:443", "reject *:*"]);
+        assert!(rules.allows_address("192.0.2.7".parse().unwrap()));
+        assert!(!rules.allows_address("10.1.2.3".parse().unwrap()));
+        assert!(policy(&["reject *:1-65534", "accept *:*"]).allows_address("192.0.2.7".parse().unwrap()));
+        assert!(!ExitPolicy::reject_all().allows_address("192.0.2.7".parse().unwrap()));
+        let no_private = ExitPolicy::from_config(&["accept *:*".to_string()], true).unwrap();
+        assert!(!no_private.allows_address("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_networks_and_mapped_ipv4_addresses() {
+        let rules = policy(&["reject [fe80::]/10:*", "reject 10.0.0.0/8:*", "accept *:*"]);
+        assert!(!rules.allows(&addr("[fe80::1]:80")));
+        assert!(rules.allows(&addr("[2001:db8::1]:80")));
+        assert!(!rules.allows(&addr("[::ffff:10.0.0.1]:80")));
+    }
+
+    #[test]
+    fn reject_private_goes_before_the_configured_rules() {
+        let rules = ExitPolicy::from_config(&["accept *:*".to_string()], true).unwrap();
+        assert!(!rules.allows(&addr("127.0.0.1:80")));
+        assert!(!rules.allows(&addr("192.168.1.1:80")));
+        assert!(!rules.allows(&addr("[::1]:80")));
+        assert!(rules.allows(&addr("192.0.2.7:80")));
+    }
+
+    #[test]
+    fn malformed_rules_are_errors() {
+        for rule in ["allow *:80", "accept", "accept *", "accept *:0", "accept *:443-80", "accept *:http", "accept 192.0.2.0/33:80", "accept example.com:80"] {
+            assert!(ExitRule::parse(rule).is_err(), "parsed '{}'", rule);
+        }
+    }
+
+    #[test]
+    fn rules_display_as_they_parse() {
+        for rule in ["accept *:*", "reject 10.0.0.0/8:25", "accept [2001:db8::]/32:80-443"] {
+            assert_eq!(ExitRule::parse(rule).unwrap().to_string(), rule);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! SENDME flow control. Each end of a circuit may only send so many data cells, on the
+//! whole circuit and on each stream, before the other end acknowledges them with a
+//! `Sendme`. The receiving end sends the circuit's `Sendme`s as cells arrive, and a
+//! stream's only once its data has been written out, so a stream nobody reads stops its
+//! own sender without holding up the rest of the circuit. A stream's receiving end only
+//! queues what its window allows, and a sender that overruns it loses the circuit.
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Semaphore;
+
+/// Data cells either end may have in flight on a circuit.
+pub const CIRCUIT_WINDOW: usize = 1000;
+/// Data cells a circuit-level `Sendme` acknowledges.
+pub const CIRCUIT_SENDME_INCREMENT: usize = 100;
+/// Data cells either end may have in flight on one stream.
+pub const STREAM_WINDOW: usize = 500;
+/// Data cells a stream-level `Sendme` acknowledges.
+pub const STREAM_SENDME_INCREMENT: usize = 50;
+
+/// Where the receiving end of a stream queues the data cells it was sent until they are
+/// written out, with `None` marking the end of the data.
+pub type StreamQueue = mpsc::Sender<Option<Vec<u8>>>;
+
+/// A queue with room for a whole stream window and the end marker after it, which is all
+/// a sender that waits for `Sendme`s can ever have queued.
+pub fn stream_queue() -> (StreamQueue, mpsc::Receiver<Option<Vec<u8>>>) {
+    mpsc::channel(STREAM_WINDOW + 1)
+}
+
+/// Queues data from the other end without waiting. Returns `false` if the queue is full,
+/// which means the other end sent more than the stream window allows. Data for a stream
+/// whose writer has gone away is dropped.
+pub fn queue(queue: &StreamQueue, data: Option<Vec<u8>>) -> bool {
+    !matches!(queue.try_send(data), Err(TrySendError::Full(_)))
+}
+
+/// How many more data cells may be sent before the other end acknowledges some.
+pub struct PackageWindow {
+    room: Semaphore,
+    size: usize,
+    increment: usize,
+}
+
+impl PackageWindow {
+    pub fn new(size: usize, increment: usize) -> Self {
+        PackageWindow { room: Semaphore::new(size), size, increment }
+    }
+
+    pub fn circuit() -> Self {
+        Self::new(CIRCUIT_WINDOW, CIRCUIT_SENDME_INCREMENT)
+    }
+
+    pub fn stream() -> Self {
+        Self::new(STREAM_WINDOW, STREAM_SENDME_INCREMENT)
+    }
+
+    /// Waits until one more data cell may be sent and counts it. Returns `false` once the
+    /// window is closed.
+    pub async fn take(&self) -> bool {
+        match self.room.acquire().await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Opens the window again for the cells a `Sendme` acknowledges. A peer acknowledging
+    /// more than was ever sent cannot grow the window past its size.
+    pub fn sendme(&self) {
+        let room = self.increment.min(self.size.saturating_sub(self.room.available_permits()));
+        self.room.add_permits(room);
+    }
+
+    /// Wakes every sender still waiting for room, which then gives up.
+    pub fn close(&self) {
+        self.room.close();
+    }
+}
+
+/// Counts data cells delivered on the receiving end and says when a `Sendme` is due.
+pub struct DeliveryCounter {
+    delivered: usize,
+    increment: usize,
+}
+
+impl DeliveryCounter {
+    pub fn circuit() -> Self {
+        DeliveryCounter { delivered: 0, increment: CIRCUIT_SENDME_INCREMENT }
+    }
+
+    pub fn stream() -> Self {
+        DeliveryCounter { delivered: 0, increment: STREAM_SENDME_INCREMENT }
+    }
+
+    /// Counts one more delivered cell. Returns `true` when a `Sendme` should be sent.
+    pub fn deliver(&mut self) -> bool {
+        self.delivered += 1;
+        if self.delivered == self.increment {
+            self.delivered = 0;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Whether `take` completes without waiting for a `Sendme`.
+    async fn has_room(window: &PackageWindow) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), window.take()).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn taking_uses_up_the_window() {
+        let window = PackageWindow::new(3, 2);
+        for _ in 0..3 {
+            assert!(has_room(&window).await);
+        }
+        assert!(!has_room(&window).await);
+    }
+
+    #[tokio::test]
+    async fn sendme_opens_the_window_by_its_increment() {
+        let window = PackageWindow::new(3, 2);
+        for _ in 0..3 {
+            window.take().await;
+        }
+        window.sendme();
+        assert!(has_room(&window).await);
+        assert!(has_room(&window).await);
+        assert!(!has_room(&window).await);
+    }
+
+    #[tokio::test]
+    async fn sendme_never_grows_the_window_past_its_size() {
+        let window = PackageWindow::new(3, 2);
+        window.take().await;
+        window.sendme();
+        window.sendme();
+        for _ in 0..3 {
+            assert!(has_room(&window).await);
+        }
+        assert!(!has_room(&window).await);
+    }
+
+    #[tokio::test]
+    async fn closing_wakes_a_waiting_sender() {
+        let window = std::sync::Arc::new(PackageWindow::new(1, 1));
+        window.take().await;
+        let waiting = tokio::spawn({
+            let window = window.clone();
+            async move { window.take().await }
+        });
+        tokio::task::yield_now().await;
+        window.close();
+        assert!(!waiting.await.unwrap());
+    }
+
+    #[test]
+    fn delivery_counter_asks_for_a_sendme_every_increment() {
+        let mut counter = DeliveryCounter::stream();
+        let due: Vec<bool> = (0..2 * STREAM_SENDME_INCREMENT).map(|_| counter.deliver()).collect();
+        assert_eq!(due.iter().filter(|&&due| due).count(), 2);
+        assert!(due[STREAM_SENDME_INCREMENT - 1] && due[2 * STREAM_SENDME_INCREMENT - 1]);
+
+        let mut counter = DeliveryCounter::circuit();
+        let sendmes = (0..CIRCUIT_WINDOW).filter(|_| counter.deliver()).count();
+        assert_eq!(sendmes, CIRCUIT_WINDOW / CIRCUIT_SENDME_INCREMENT);
+    }
+
+    #[test]
+    fn stream_queue_holds_a_window_and_the_end() {
+        let (queue_tx, _queue_rx) = stream_queue();
+        for _ in 0..STREAM_WINDOW {
+            assert!(queue(&queue_tx, Some(vec![0])));
+        }
+        assert!(queue(&queue_tx, None));
+        assert!(!queue(&queue_tx, Some(vec![0])));
+    }
+
+    #[test]
+    fn stream_queue_drops_data_for_a_closed_stream() {
+        let (queue_tx, queue_rx) = stream_queue();
+        drop(queue_rx);
+        assert!(queue(&queue_tx, Some(vec![0])));
+    }
+}
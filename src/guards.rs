@@ -0,0 +1,316 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Entry guards: the few nodes the proxy uses as the first hop of every circuit. Picking
+//! the entry at random for each circuit would eventually show our address to every node;
+//! sticking to a small set for weeks limits that to the guards. The set is saved so it
+//! survives restarts.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use rand::seq::SliceRandom;
+
+use crate::crypto;
+use crate::directory_protocol::NodeFlag;
+use crate::path::Candidate;
+
+/// Number of usable guards the proxy keeps.
+pub const GUARD_SET_SIZE: usize = 3;
+/// Most guards kept at once, down ones included. Down guards stay in the set until they
+/// are dropped, so without a cap an outage on our side would go on adding new guards and
+/// show our address to most of the network.
+const MAX_SAMPLED_GUARDS: usize = 2 * GUARD_SET_SIZE;
+/// How long a guard that failed is skipped before it is tried again.
+const GUARD_RETRY_SECS: u64 = 10 * 60;
+/// A guard down for this long is replaced.
+const GUARD_DOWN_DROP_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Bumped whenever [`GuardSet`] changes shape, so an old state file is reported instead of
+/// being misread.
+const STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryGuard {
+    pub node_id: [u8; 32],
+    /// Address the guard had when last seen in a consensus, for display.
+    pub address: SocketAddr,
+    pub added: u64,
+    /// When the guard is rotated out for a new one.
+    pub expires: u64,
+    /// Set while the guard is failing, to the time of the first failure.
+    pub down_since: Option<u64>,
+    pub last_failure: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GuardSet {
+    /// In order of preference: circuits use the first guard that fits.
+    pub guards: Vec<EntryGuard>,
+}
+
+impl EntryGuard {
+    fn is_retry_due(&self, now: u64) -> bool {
+        self.down_since.is_none() || now >= self.last_failure + GUARD_RETRY_SECS
+    }
+}
+
+impl GuardSet {
+    /// Loads the guard set saved at `file_path`, or an empty set if there is none yet.
+    pub fn load(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        if !Path::new(file_path).exists() {
+            return Ok(GuardSet::default());
+        }
+        let bytes = fs::read(file_path)?;
+        let version: u32 = bincode::deserialize(&bytes)
+            .map_err(|e| format!("Failed to decode guard state in {}: {}", file_path, e))?;
+        if version != STATE_VERSION {
+            println!("[PROXY] Ignoring guard state in {} from another version; picking new guards.", file_path);
+            return Ok(GuardSet::default());
+        }
+        let (_, guards): (u32, GuardSet) = bincode::deserialize(&bytes)
+            .map_err(|e| format!("Failed to decode guard state in {}: {}", file_path, e))?;
+        Ok(guards)
+    }
+
+    pub fn save(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let bytes = bincode::serialize(&(STATE_VERSION, self))?;
+        crypto::write_private_file(file_path, &bytes)
+    }
+
+    /// Rotates out expired guards and guards down for too long, then tops the set up with
+    /// random Guard nodes from the consensus. Down guards due for a retry count as usable,
+    /// so they are tried again before new ones are added, and the set never grows past
+    /// [`MAX_SAMPLED_GUARDS`]. Returns whether the set changed.
+    pub fn refresh(&mut self, candidates: &[Candidate], now: u64, lifetime_secs: u64) -> bool {
+        let before = self.guards.len();
+        self.guards.retain(|guard| {
+            now < guard.expires && guard.down_since.is_none_or(|since| now < since + GUARD_DOWN_DROP_SECS)
+        });
+        let mut changed = self.guards.len() != before;
+
+        for guard in self.guards.iter_mut() {
+            if let Some(candidate) = find_guard(candidates, &guard.node_id) {
+                changed |= guard.address != candidate.info.address;
+                guard.address = candidate.info.address;
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        while self.usable_count(candidates, now) < GUARD_SET_SIZE && self.guards.len() < MAX_SAMPLED_GUARDS {
+            let fresh: Vec<&Candidate> = candidates.iter()
+                .filter(|c| c.info.has_flag(&NodeFlag::Guard))
+                .filter(|c| !self.guards.iter().any(|g| g.node_id == crypto::node_id(&c.info.public_key)))
+                .collect();
+            let Some(candidate) = fresh.choose(&mut rng) else { break };
+            self.guards.push(EntryGuard {
+                node_id: crypto::node_id(&candidate.info.public_key),
+                address: candidate.info.address,
+                added: now,
+                expires: now + lifetime_secs,
+                down_since: None,
+                last_failure: 0,
+            });
+            changed = true;
+        }
+        changed
+    }
+
+    fn usable_count(&self, candidates: &[Candidate], now: u64) -> usize {
+        self.guards.iter()
+            .filter(|g| g.is_retry_due(now) && find_guard(candidates, &g.node_id).is_some())
+            .count()
+    }
+
+    /// Guards to try, in order of preference: those in the consensus that are up or due
+    /// for a retry. If every listed guard is down, all of them, so a short outage on our
+    /// side doesn't leave the proxy without a first hop.
+    pub fn usable(&self, candidates: &[Candidate], now: u64) -> Vec<[u8; 32]> {
+        let listed: Vec<&EntryGuard> = self.guards.iter()
+            .filter(|g| find_guard(candidates, &g.node_id).is_some())
+            .collect();
+        let ready: Vec<[u8; 32]> = listed.iter().filter(|g| g.is_retry_due(now)).map(|g| g.node_id).collect();
+        if ready.is_empty() {
+            listed.iter().map(|g| g.node_id).collect()
+        } else {
+            ready
+        }
+    }
+
+    /// Records a failed attempt to use a guard. Returns whether the set changed.
+    pub fn mark_down(&mut self, node_id: &[u8; 32], now: u64) -> bool {
+        let Some(guard) = self.guards.iter_mut().find(|g| &g.node_id == node_id) else { return false };
+        guard.down_since.get_or_insert(now);
+        guard.last_failure = now;
+        true
+    }
+
+    /// Records a successful circuit through a guard. Returns whether the set changed.
+    pub fn mark_up(&mut self, node_id: &[u8; 32]) -> bool {
+        let Some(guard) = self.guards.iter_mut().find(|g| &g.node_id == node_id) else { return false };
+        guard.down_since.take().is_some()
+    }
+}
+
+fn find_guard<'a>(candidates: &'a [Candidate], node_id: &[u8; 32]) -> Option<&'a Candidate> {
+    candidates.iter().find(|c| c.info.has_flag(&NodeFlag::Guard) && &crypto::node_id(&c.info.public_key) == node_id)
+}
+
+/// Prints the saved guard set for the `status` command.
+pub fn print_status(file_path: &str, now: u64) -> Result<(), Box<dyn Error>> {
+    let set = GuardSet::load(file_path)?;
+    if set.guards.is_empty() {
+        println!("No entry guards chosen yet. They are picked the first time the proxy starts.");
+        return Ok(());
+    }
+    println!("Entry guards ({}), in order of preference:", file_path);
+    for (i, guard) in set.guards.iter().enumerate() {
+        let state = match guard.down_since {
+            None => "up".to_string(),
+            Some(since) => format!("down for {}", format_duration(now.saturating_sub(since))),
+        };
+        println!(
+            "  {}. {}  {}  added {} ago, rotates in {}, {}",
+            i + 1,
+            guard.address,
+            &crypto::to_hex(&guard.node_id)[..16],
+            format_duration(now.saturating_sub(guard.added)),
+            format_duration(guard.expires.saturating_sub(now)),
+            state,
+        );
+    }
+    Ok(())
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 2 * 24 * 60 * 60 => format!("{} days", s / (24 * 60 * 60)),
+        s if s >= 2 * 60 * 60 => format!("{} hours", s / (60 * 60)),
+        s if s >= 2 * 60 => format!("{} minutes", s / 60),
+        s => format!("{} seconds", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory_protocol::NodeInfo;
+    use crate::exit_policy::ExitPolicy;
+    use crate::protocol::ProtocolSupport;
+    use rsa::RsaPrivateKey;
+
+    const LIFETIME_SECS: u64 = 30 * 24 * 60 * 60;
+
+    fn candidate(i: u8, flags: &[NodeFlag]) -> Candidate {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        Candidate {
+            info: NodeInfo {
+                address: format!("10.{}.0.1:9001", i).parse().unwrap(),
+                public_key: key.to_public_key(),
+                onion_key: Some([0; 32]),
+                bandwidth: 0,
+                published: 0,
+                exit_policy: ExitPolicy::reject_all(),
+                flags: flags.to_vec(),
+                protocol: ProtocolSupport::ours(),
+            },
+            operator: format!("member{}", i),
+        }
+    }
+
+    fn guard_nodes(count: u8) -> Vec<Candidate> {
+        (0..count).map(|i| candidate(i, &[NodeFlag::Guard])).collect()
+    }
+
+    #[test]
+    fn guards_are_picked_from_guard_nodes_and_kept() {
+        let mut candidates = guard_nodes(4);
+        candidates.extend((4..10).map(|i| candidate(i, &[])));
+        let mut set = GuardSet::default();
+        assert!(set.refresh(&candidates, 1000, LIFETIME_SECS));
+        assert_eq!(set.guards.len(), GUARD_SET_SIZE);
+        for guard in &set.guards {
+            assert!(find_guard(&candidates, &guard.node_id).is_some());
+        }
+        let chosen: Vec<[u8; 32]> = set.guards.iter().map(|g| g.node_id).collect();
+        assert!(!set.refresh(&candidates, 2000, LIFETIME_SECS));
+        assert_eq!(set.usable(&candidates, 2000), chosen);
+
+        // Guards are replaced once their lifetime is over.
+        assert!(set.refresh(&candidates, 1000 + LIFETIME_SECS, LIFETIME_SECS));
+        assert!(set.guards.iter().all(|g| g.added == 1000 + LIFETIME_SECS));
+    }
+
+    #[test]
+    fn saved_guards_load_back() {
+        let file = std::env::temp_dir().join(format!("giralnet-guards-{}.bin", std::process::id()));
+        let file = file.to_string_lossy().into_owned();
+        assert!(GuardSet::load(&file).unwrap().guards.is_empty());
+
+        let candidates = guard_nodes(3);
+        let mut set = GuardSet::default();
+        set.refresh(&candidates, 1000, LIFETIME_SECS);
+        set.mark_down(&set.guards[1].node_id.clone(), 1500);
+        set.save(&file).unwrap();
+        let loaded = GuardSet::load(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        let ids = |set: &GuardSet| set.guards.iter().map(|g| (g.node_id, g.down_since)).collect::<Vec<_>>();
+        assert_eq!(ids(&loaded), ids(&set));
+    }
+
+    #[test]
+    fn down_guards_are_retried_before_the_set_grows() {
+        let candidates = guard_nodes(12);
+        let mut set = GuardSet::default();
+        set.refresh(&candidates, 1000, LIFETIME_SECS);
+        let first = set.guards[0].node_id;
+        set.mark_down(&first, 1000);
+
+        // While it waits for its retry another guard stands in, and once the retry is due
+        // the down guard is tried again instead of adding more.
+        assert!(set.refresh(&candidates, 1001, LIFETIME_SECS));
+        assert_eq!(set.guards.len(), GUARD_SET_SIZE + 1);
+        assert!(!set.usable(&candidates, 1001).contains(&first));
+        assert!(!set.refresh(&candidates, 1000 + GUARD_RETRY_SECS, LIFETIME_SECS));
+        assert!(set.usable(&candidates, 1000 + GUARD_RETRY_SECS).contains(&first));
+        set.mark_up(&first);
+        assert_eq!(set.usable(&candidates, 1000 + GUARD_RETRY_SECS)[0], first);
+    }
+
+    #[test]
+    fn an_outage_does_not_grow_the_set_past_its_cap() {
+        let candidates = guard_nodes(12);
+        let mut set = GuardSet::default();
+        let mut now = 1000;
+        for _ in 0..10 {
+            set.refresh(&candidates, now, LIFETIME_SECS);
+            for guard in set.guards.clone() {
+                set.mark_down(&guard.node_id, now);
+            }
+            now += 60;
+        }
+        assert_eq!(set.guards.len(), MAX_SAMPLED_GUARDS);
+        // With every guard down, all of them are still offered rather than none.
+        assert_eq!(set.usable(&candidates, now).len(), MAX_SAMPLED_GUARDS);
+
+        // Guards down for too long are dropped, which makes room for new ones.
+        assert!(set.refresh(&candidates, 1000 + GUARD_DOWN_DROP_SECS + 600, LIFETIME_SECS));
+        assert_eq!(set.guards.len(), GUARD_SET_SIZE);
+        assert!(set.guards.iter().all(|g| g.down_since.is_none()));
+    }
+}
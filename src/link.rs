@@ -0,0 +1,480 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Link connections: one TLS connection between two neighbors (two relays, or a proxy and
+//! its entry) carrying any number of circuits, told apart by the circuit ID in front of
+//! every cell.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::OnceCell;
+use tokio_rustls::rustls::{ClientConfig, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::flow::CIRCUIT_WINDOW;
+use crate::protocol::{self, Negotiated, CELL_LEN, FEATURE_DESTROY_REASONS};
+use crate::tls_client;
+
+pub type CircId = u32;
+
+/// A circuit ID, a command and a payload the size of a relay cell.
+pub const LINK_CELL_LEN: usize = 4 + 1 + CELL_LEN;
+
+/// Cells waiting to be written to a link, shared by all of its circuits.
+const LINK_QUEUE: usize = 256;
+/// Cells outside the circuit window a circuit's queue has room for: `Sendme`s, and the
+/// `Begin`, `End` and `Resolve` cells of its streams.
+const CONTROL_CELLS: usize = 1024;
+/// Cells read from a link waiting for their circuit to take them: a full circuit window of
+/// data and the control cells sent alongside it. A peer that overflows it either ignored
+/// the window or is flooding the circuit with control cells, and loses the circuit.
+const CIRCUIT_QUEUE: usize = CIRCUIT_WINDOW + CONTROL_CELLS;
+/// Circuits the peer may have open on one link at a time. Further `Create`s are refused.
+const MAX_LINK_CIRCUITS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LinkCommand {
+    /// Opens a circuit. The payload holds the proxy's handshake with the receiving hop.
+    Create = 1,
+    /// Answers `Create`. The payload holds the hop's handshake reply.
+    Created = 2,
+    /// A relay cell moving along the circuit.
+    Relay = 3,
+    /// Tears the circuit down. Each hop forgets the circuit and passes it on, with the
+    /// [`DestroyReason`] in the first payload byte.
+    Destroy = 4,
+}
+
+/// Why a circuit was torn down. Hops pass the reason on unchanged, so both ends of the
+/// circuit learn what happened wherever it happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DestroyReason {
+    /// Sent by relays that predate destroy reasons, or a value this side does not know.
+    Unspecified = 0,
+    /// The proxy closed the circuit.
+    Requested = 1,
+    /// A link along the circuit was lost.
+    ConnectionLost = 2,
+    /// A hop sent something it should not have, like a cell failing integrity checks.
+    Protocol = 3,
+    /// A hop already carries as many circuits as it allows on the link.
+    ResourceLimit = 4,
+}
+
+impl From<u8> for DestroyReason {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => DestroyReason::Requested,
+            2 => DestroyReason::ConnectionLost,
+            3 => DestroyReason::Protocol,
+            4 => DestroyReason::ResourceLimit,
+            _ => DestroyReason::Unspecified,
+        }
+    }
+}
+
+impl fmt::Display for DestroyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            DestroyReason::Unspecified => "no reason given",
+            DestroyReason::Requested => "closed by the proxy",
+            DestroyReason::ConnectionLost => "a connection along the circuit was lost",
+            DestroyReason::Protocol => "a hop broke the protocol",
+            DestroyReason::ResourceLimit => "a hop has no room for more circuits",
+        };
+        f.write_str(text)
+    }
+}
+
+impl TryFrom<u8> for LinkCommand {
+    type Error = Box<dyn Error>;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(LinkCommand::Create),
+            2 => Ok(LinkCommand::Created),
+            3 => Ok(LinkCommand::Relay),
+            4 => Ok(LinkCommand::Destroy),
+            other => Err(format!("Unknown link command {}", other).into()),
+        }
+    }
+}
+
+pub struct LinkCell {
+    pub circ_id: CircId,
+    pub command: LinkCommand,
+    pub payload: [u8; CELL_LEN],
+}
+
+impl LinkCell {
+    pub fn new(circ_id: CircId, command: LinkCommand, payload: [u8; CELL_LEN]) -> Self {
+        LinkCell { circ_id, command, payload }
+    }
+
+    fn destroy(circ_id: CircId, reason: DestroyReason) -> Self {
+        let mut payload = [0u8; CELL_LEN];
+        payload[0] = reason as u8;
+        LinkCell::new(circ_id, LinkCommand::Destroy, payload)
+    }
+
+    /// Why the circuit is torn down, for a `Destroy` cell.
+    pub fn destroy_reason(&self) -> DestroyReason {
+        DestroyReason::from(self.payload[0])
+    }
+
+    fn encode(&self) -> [u8; LINK_CELL_LEN] {
+        let mut bytes = [0u8; LINK_CELL_LEN];
+        bytes[..4].copy_from_slice(&self.circ_id.to_be_bytes());
+        bytes[4] = self.command as u8;
+        bytes[5..].copy_from_slice(&self.payload);
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8; LINK_CELL_LEN]) -> Result<Self, Box<dyn Error>> {
+        let circ_id = CircId::from_be_bytes(bytes[..4].try_into()?);
+        let command = LinkCommand::try_from(bytes[4])?;
+        Ok(LinkCell { circ_id, command, payload: bytes[5..].try_into()? })
+    }
+}
+
+/// A circuit the peer opened on a link, with its `Create` cell waiting in `cells`.
+pub struct IncomingCircuit {
+    pub link: Arc<Link>,
+    pub circ_id: CircId,
+    pub cells: mpsc::Receiver<LinkCell>,
+}
+
+/// One link connection and the table of circuits on it. Circuits are only opened by the
+/// side that opened the link, which also picks their IDs.
+pub struct Link {
+    /// Address the link was opened to, or the peer's address for a link the peer opened.
+    pub peer: SocketAddr,
+    /// Member the peer's certificate was issued to. Proxies present none.
+    pub peer_member: Option<String>,
+    /// What this side and the peer agreed to speak in their hellos.
+    pub protocol: Negotiated,
+    writer: mpsc::Sender<LinkCell>,
+    circuits: Mutex<HashMap<CircId, mpsc::Sender<LinkCell>>>,
+    next_circ_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+impl Link {
+    /// Starts the tasks moving cells over `stream`. Circuits the peer opens are handed to
+    /// `incoming`; without it, they are ignored.
+    pub fn start<S>(stream: S, peer: SocketAddr, peer_member: Option<String>, protocol: Negotiated, incoming: Option<mpsc::Sender<IncomingCircuit>>) -> Arc<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (mut reader, mut writer) = io::split(stream);
+        let (tx, mut rx) = mpsc::channel::<LinkCell>(LINK_QUEUE);
+        let link = Arc::new(Link {
+            peer,
+            peer_member,
+            protocol,
+            writer: tx,
+            circuits: Mutex::new(HashMap::new()),
+            next_circ_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+        });
+
+        // Ends once every handle to the link is gone, or as soon as a write fails.
+        let writer_link = Arc::downgrade(&link);
+        tokio::spawn(async move {
+            while let Some(cell) = rx.recv().await {
+                if writer.write_all(&cell.encode()).await.is_err() {
+                    break;
+                }
+                if rx.is_empty() && writer.flush().await.is_err() {
+                    break;
+                }
+            }
+            if let Some(link) = writer_link.upgrade() {
+                link.close();
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        let reader_link = link.clone();
+        tokio::spawn(async move {
+            let link = reader_link;
+            let mut bytes = [0u8; LINK_CELL_LEN];
+            while reader.read_exact(&mut bytes).await.is_ok() {
+                let Ok(cell) = LinkCell::decode(&bytes) else { break };
+                let circ_id = cell.circ_id;
+                let circuit = link.circuits.lock().unwrap().get(&circ_id).cloned();
+                match circuit {
+                    // Waiting for one circuit's queue would hold up every circuit on the link.
+                    Some(circuit) => match circuit.try_send(cell) {
+                        // A circuit that stopped reading has gone away; its cells are dropped.
+                        Ok(()) | Err(TrySendError::Closed(_)) => {}
+                        Err(TrySendError::Full(_)) => {
+                            if link.remove_circuit(circ_id) {
+                                link.refuse_circuit(circ_id, DestroyReason::Protocol);
+                            }
+                        }
+                    },
+                    None if cell.command == LinkCommand::Create => {
+                        let Some(incoming) = &incoming else { continue };
+                        if link.circuits.lock().unwrap().len() >= MAX_LINK_CIRCUITS {
+                            link.refuse_circuit(circ_id, DestroyReason::ResourceLimit);
+                            continue;
+                        }
+                        let (tx, rx) = mpsc::channel(CIRCUIT_QUEUE);
+                        let _ = tx.try_send(cell);
+                        link.circuits.lock().unwrap().insert(circ_id, tx);
+                        let circuit = IncomingCircuit { link: link.clone(), circ_id, cells: rx };
+                        if incoming.send(circuit).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Cells for circuits already torn down on this side.
+                    None => {}
+                }
+            }
+            link.close();
+        });
+
+        link
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Marks the link closed and drops its circuit table, which ends every circuit on it.
+    /// Circuits treat that end like a `Destroy` for [`DestroyReason::ConnectionLost`].
+    fn close(&self) {
+        let mut circuits = self.circuits.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        circuits.clear();
+    }
+
+    /// Picks an ID for a new circuit to the peer and registers it, returning the cells the
+    /// peer will send on it. `None` once the link is closed.
+    pub fn add_circuit(&self) -> Option<(CircId, mpsc::Receiver<LinkCell>)> {
+        let mut circuits = self.circuits.lock().unwrap();
+        if self.is_closed() {
+            return None;
+        }
+        // IDs wrap around on long-lived links; 0 is never used and IDs of circuits still
+        // open are skipped.
+        let circ_id = loop {
+            let circ_id = self.next_circ_id.fetch_add(1, Ordering::SeqCst);
+            if circ_id != 0 && !circuits.contains_key(&circ_id) {
+                break circ_id;
+            }
+        };
+        let (tx, rx) = mpsc::channel(CIRCUIT_QUEUE);
+        circuits.insert(circ_id, tx);
+        Some((circ_id, rx))
+    }
+
+    /// Forgets a circuit, so cells for it are dropped. Returns whether it was still known.
+    pub fn remove_circuit(&self, circ_id: CircId) -> bool {
+        self.circuits.lock().unwrap().remove(&circ_id).is_some()
+    }
+
+    /// Forgets a circuit and tells the peer to tear it down, unless it is already gone.
+    pub async fn destroy_circuit(&self, circ_id: CircId, reason: DestroyReason) {
+        if self.remove_circuit(circ_id) {
+            let _ = self.send(self.destroy_cell(circ_id, reason)).await;
+        }
+    }
+
+    /// Tells the peer a circuit it opened is gone, from a detached task: the reader must not
+    /// wait for room in the writer's queue while cells for other circuits pile up.
+    fn refuse_circuit(self: &Arc<Self>, circ_id: CircId, reason: DestroyReason) {
+        let cell = self.destroy_cell(circ_id, reason);
+        let link = self.clone();
+        tokio::spawn(async move {
+            let _ = link.send(cell).await;
+        });
+    }
+
+    /// A `Destroy` for the peer. The reason is only given to peers that read it.
+    fn destroy_cell(&self, circ_id: CircId, reason: DestroyReason) -> LinkCell {
+        let reason = if self.protocol.has(FEATURE_DESTROY_REASONS) { reason } else { DestroyReason::Unspecified };
+        LinkCell::destroy(circ_id, reason)
+    }
+
+    pub async fn send(&self, cell: LinkCell) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.writer.send(cell).await.map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// The links this side opened, one per neighbor, shared by every circuit to that neighbor.
+pub struct LinkManager {
+    connector: TlsConnector,
+    /// One slot per neighbor, filled once its link is up. Connecting happens outside the
+    /// map's lock, so a slow neighbor only holds up circuits to that neighbor.
+    links: Mutex<HashMap<SocketAddr, Arc<OnceCell<Arc<Link>>>>>,
+}
+
+impl LinkManager {
+    pub fn new(config: ClientConfig) -> Self {
+        LinkManager {
+            connector: TlsConnector::from(Arc::new(config)),
+            links: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The open link to `addr`, connecting first if there is none. `operator` is the member
+    /// the directory lists as running the node there, and the link is refused if the node's
+    /// certificate was issued to anyone else.
+    pub async fn get(&self, addr: SocketAddr, operator: &str) -> Result<Arc<Link>, Box<dyn Error>> {
+        let slot = {
+            let mut links = self.links.lock().unwrap();
+            match links.get(&addr) {
+                Some(slot) if slot.get().is_none_or(|link| !link.is_closed()) => slot.clone(),
+                // No link yet, or only a closed one: start over with an empty slot.
+                _ => {
+                    let slot = Arc::new(OnceCell::new());
+                    links.insert(addr, slot.clone());
+                    slot
+                }
+            }
+        };
+        // Circuits asking while the link is being set up wait for it, and try again
+        // themselves if it fails.
+        let link = slot.get_or_try_init(|| self.connect(addr)).await?.clone();
+        if link.peer_member.as_deref() != Some(operator) {
+            return Err(format!("The node at {} is not run by its listed operator", addr).into());
+        }
+        Ok(link)
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<Arc<Link>, Box<dyn Error>> {
+        let tcp = TcpStream::connect(addr).await?;
+        let mut tls = self.connector.connect(ServerName::IpAddress(addr.ip()), tcp).await?;
+        let peer_member = tls_client::peer_member(tls.get_ref().1.peer_certificates())?;
+        let protocol = protocol::client_hello(&mut tls, &format!("the node at {}", addr)).await?;
+        Ok(Link::start(tls, addr, peer_member, protocol, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{FEATURES, PROTOCOL_VERSION};
+
+    /// A link between a proxy and a relay over an in-memory connection, with the circuits
+    /// the proxy opens.
+    fn linked() -> (Arc<Link>, Arc<Link>, mpsc::Receiver<IncomingCircuit>) {
+        let (proxy_end, relay_end) = io::duplex(64 * 1024);
+        let protocol = Negotiated { version: PROTOCOL_VERSION, features: FEATURES.iter().map(|f| f.to_string()).collect() };
+        let peer: SocketAddr = "192.0.2.7:9001".parse().unwrap();
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        let proxy = Link::start(proxy_end, peer, None, protocol.clone(), None);
+        let relay = Link::start(relay_end, peer, None, protocol, Some(incoming_tx));
+        (proxy, relay, incoming_rx)
+    }
+
+    fn cell(circ_id: CircId, command: LinkCommand, tag: u8) -> LinkCell {
+        LinkCell::new(circ_id, command, [tag; CELL_LEN])
+    }
+
+    #[tokio::test]
+    async fn circuits_share_a_link_without_mixing_cells() {
+        let (proxy, relay, mut incoming) = linked();
+        let (first_id, mut first) = proxy.add_circuit().unwrap();
+        let (second_id, mut second) = proxy.add_circuit().unwrap();
+        assert_ne!(first_id, second_id);
+        for (circ_id, tag) in [(first_id, 1), (second_id, 2)] {
+            proxy.send(cell(circ_id, LinkCommand::Create, tag)).await.unwrap();
+        }
+
+        let mut opened = Vec::new();
+        for _ in 0..2 {
+            let mut circuit = incoming.recv().await.unwrap();
+            let create = circuit.cells.recv().await.unwrap();
+            assert_eq!(create.command, LinkCommand::Create);
+            assert_eq!(create.payload[0], if circuit.circ_id == first_id { 1 } else { 2 });
+            relay.send(cell(circuit.circ_id, LinkCommand::Created, create.payload[0])).await.unwrap();
+            opened.push(circuit);
+        }
+        assert_eq!(first.recv().await.unwrap().payload[0], 1);
+        assert_eq!(second.recv().await.unwrap().payload[0], 2);
+
+        // Tearing one circuit down leaves the other working.
+        relay.destroy_circuit(first_id, DestroyReason::Protocol).await;
+        let destroy = first.recv().await.unwrap();
+        assert_eq!(destroy.command, LinkCommand::Destroy);
+        assert_eq!(destroy.destroy_reason(), DestroyReason::Protocol);
+        proxy.send(cell(second_id, LinkCommand::Relay, 3)).await.unwrap();
+        let second_relay = opened.iter_mut().find(|c| c.circ_id == second_id).unwrap();
+        assert_eq!(second_relay.cells.recv().await.unwrap().payload[0], 3);
+    }
+
+    #[tokio::test]
+    async fn circuit_ids_in_use_are_skipped_when_they_wrap() {
+        let (proxy, _relay, _incoming) = linked();
+        let (first_id, _first) = proxy.add_circuit().unwrap();
+        assert_eq!(first_id, 1);
+        proxy.next_circ_id.store(CircId::MAX, Ordering::SeqCst);
+        assert_eq!(proxy.add_circuit().unwrap().0, CircId::MAX);
+        assert_eq!(proxy.add_circuit().unwrap().0, 2);
+    }
+
+    #[tokio::test]
+    async fn overflowing_a_circuit_queue_loses_the_circuit() {
+        let (proxy, relay, mut incoming) = linked();
+        let (circ_id, mut cells) = proxy.add_circuit().unwrap();
+        proxy.send(cell(circ_id, LinkCommand::Create, 0)).await.unwrap();
+        // The relay never reads the circuit, so everything after the Create stays queued.
+        let _circuit = incoming.recv().await.unwrap();
+        for _ in 0..CIRCUIT_QUEUE {
+            proxy.send(cell(circ_id, LinkCommand::Relay, 0)).await.unwrap();
+        }
+        let destroy = cells.recv().await.unwrap();
+        assert_eq!(destroy.command, LinkCommand::Destroy);
+        assert_eq!(destroy.destroy_reason(), DestroyReason::Protocol);
+        assert!(!relay.remove_circuit(circ_id));
+    }
+
+    #[tokio::test]
+    async fn creates_past_the_circuit_limit_are_refused() {
+        let (proxy, relay, mut incoming) = linked();
+        tokio::spawn(async move {
+            let mut circuits = Vec::new();
+            while let Some(circuit) = incoming.recv().await {
+                circuits.push(circuit);
+            }
+        });
+        let mut circuits = Vec::new();
+        for _ in 0..=MAX_LINK_CIRCUITS {
+            let (circ_id, cells) = proxy.add_circuit().unwrap();
+            proxy.send(cell(circ_id, LinkCommand::Create, 0)).await.unwrap();
+            circuits.push(cells);
+        }
+        let refused = circuits.last_mut().unwrap().recv().await.unwrap();
+        assert_eq!(refused.command, LinkCommand::Destroy);
+        assert_eq!(refused.destroy_reason(), DestroyReason::ResourceLimit);
+        assert_eq!(relay.circuits.lock().unwrap().len(), MAX_LINK_CIRCUITS);
+    }
+}
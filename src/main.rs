@@ -18,18 +18,30 @@ mod crypto;
 mod protocol;
 mod node;
 mod proxy;
+mod socks;
+mod exit_policy;
+mod path;
+mod guards;
+mod link;
+mod flow;
 mod directory;
 mod directory_protocol;
+mod directory_store;
 mod tls_setup;
 mod tls_client;
 mod config;
 mod tui;
 
-use config::{Config, Mode};
+use config::Mode;
 use std::error::Error;
 
 #[tokio::main]
 async fn main() {
+    // Optional command, e.g. `giralnet rotate-key` to replace a node's identity key, or
+    // `giralnet invite <name>` to issue an invite from the directory.
+    let command = std::env::args().nth(1);
+    let argument = std::env::args().nth(2);
+
     tui::show_splash_screen();
 
     let cfg = match config::load_config() {
@@ -45,35 +57,67 @@ async fn main() {
         }
     };
 
+    if command.as_deref() == Some("rotate-key") && cfg.mode != Mode::Node {
+        eprintln!("The rotate-key command is only available in Node mode. Exiting.");
+        return;
+    }
+
+    if command.as_deref() == Some("status") {
+        match cfg.mode {
+            Mode::Proxy => {
+                if let Err(e) = guards::print_status(&cfg.proxy.guard_state_file, directory_protocol::unix_now()) {
+                    eprintln!("Error: {}", e);
+                }
+            }
+            _ => eprintln!("The status command is only available in Proxy mode."),
+        }
+        return;
+    }
+
+    // Membership commands do their job and exit instead of starting the network.
+    if let Some(name @ ("init-ca" | "invite" | "revoke" | "members" | "enroll")) = command.as_deref() {
+        if let Err(e) = run_member_command(&cfg, name, argument.as_deref()).await {
+            eprintln!("Error: {}", e);
+        }
+        return;
+    }
+
     println!("[LAUNCHER] Starting Giraldo Network in {:?} mode...", cfg.mode);
     
     let result: Result<(), Box<dyn Error>> = match cfg.mode {
         Mode::Directory => {
             directory::run(
-                &cfg.directory.listen_addr,
-                &cfg.directory.secret,
-                &cfg.tls.cert_path,
-                &cfg.tls.key_path,
+                &cfg.directory,
+                &cfg.tls,
             ).await
         }
         Mode::Node => {
             let dir_server = Some(cfg.directory.listen_addr.as_str());
-            let dir_secret = Some(cfg.directory.secret.as_str());
             let ca_cert = Some(cfg.tls.ca_cert_path.as_str());
+            let rotate_key = command.as_deref() == Some("rotate-key");
+
+            let is_new_key = rotate_key || !std::path::Path::new(&cfg.node.key_file).exists();
+            let passphrase = match node_key_passphrase(&cfg, is_new_key) {
+                Ok(passphrase) => passphrase,
+                Err(e) => {
+                    eprintln!("Could not read the key passphrase: {}. Exiting.", e);
+                    return;
+                }
+            };
 
             node::run(
-                &cfg.node.listen_addr,
-                &cfg.node.key_file,
+                &cfg.node,
+                passphrase.as_deref(),
+                rotate_key,
                 dir_server,
-                dir_secret,
                 ca_cert,
             ).await
         }
         Mode::Proxy => {
             proxy::run(
                 &cfg.directory.listen_addr,
-                &cfg.directory.secret,
                 &cfg.tls.ca_cert_path,
+                &cfg.proxy,
             ).await
         }
     };
@@ -84,4 +128,52 @@ async fn main() {
         eprintln!("Press Enter to exit.");
         let _ = std::io::stdin().read_line(&mut String::new());
     }
-}
\ No newline at end of file
+}
+
+/// Handles the `init-ca`, `invite`, `revoke` and `members` commands of the directory and the
+/// `enroll` command of nodes and proxies.
+async fn run_member_command(cfg: &config::Config, command: &str, argument: Option<&str>) -> Result<(), Box<dyn Error>> {
+    match (command, &cfg.mode) {
+        ("init-ca", Mode::Directory) => {
+            let tls = &cfg.tls;
+            tls_setup::generate_team_ca(&tls.ca_cert_path, &tls.ca_key_path, &tls.cert_path, &tls.key_path)?;
+            println!("Send '{}' to every member. Members enrolled before have to enroll again to get a certificate.", tls.ca_cert_path);
+        }
+        ("invite", Mode::Directory) => {
+            let name = argument.ok_or("Usage: giralnet invite <member name>")?;
+            let invite = directory::create_invite(&cfg.directory, name)?;
+            println!("Invite for '{}' (valid for 7 days, can be used once):\n\n{}\n", name, invite);
+            println!("The member runs 'giralnet enroll <invite>' in their Node or Proxy folder.");
+        }
+        ("revoke", Mode::Directory) => {
+            let member_id = argument.ok_or("Usage: giralnet revoke <member id>")?;
+            directory::revoke_member(&cfg.directory, member_id)?;
+            println!("Member {} revoked. The directory refuses them from now on.", member_id);
+        }
+        ("members", Mode::Directory) => directory::list_members(&cfg.directory)?,
+        ("enroll", Mode::Node | Mode::Proxy) => {
+            let invite = argument.ok_or("Usage: giralnet enroll <invite>")?;
+            let (member_key_file, member_cert_file, passphrase) = if cfg.mode == Mode::Node {
+                let is_new_key = !std::path::Path::new(&cfg.node.member_key_file).exists();
+                (&cfg.node.member_key_file, &cfg.node.member_cert_file, node_key_passphrase(cfg, is_new_key)?)
+            } else {
+                (&cfg.proxy.member_key_file, &cfg.proxy.member_cert_file, None)
+            };
+            let member_key = crypto::load_or_create_identity_key(member_key_file, passphrase.as_deref())?;
+            let (member_id, certificate) = directory_protocol::enroll(&cfg.directory.listen_addr, &cfg.tls.ca_cert_path, &member_key, invite).await?;
+            std::fs::write(member_cert_file, certificate)?;
+            println!("Enrolled with the Directory Server as member {}. Certificate saved to {}.", member_id, member_cert_file);
+        }
+        ("enroll", Mode::Directory) => return Err("The enroll command is only available in Node and Proxy mode".into()),
+        (_, _) => return Err(format!("The {} command is only available in Directory mode", command).into()),
+    }
+    Ok(())
+}
+
+/// Asks for the passphrase protecting a node's identity and member keys, if the node encrypts them.
+fn node_key_passphrase(cfg: &config::Config, is_new_key: bool) -> Result<Option<String>, Box<dyn Error>> {
+    if !cfg.node.encrypt_key {
+        return Ok(None);
+    }
+    Ok(Some(tui::prompt_key_passphrase(is_new_key)?))
+}
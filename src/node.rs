@@ -18,192 +18,915 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use crate::{
     crypto,
-    protocol::{CircuitMessage, HandshakeMessage, OnionLayer, StreamID},
-    directory_protocol::{DirectoryRequest, DirectoryResponse, NodeInfo},
+    protocol::{self, Cell, CircuitMessage, ExtendFailReason, MessageKind, NtorHandshake, NtorReply, ProtocolSupport, EndReason, ResolveAnswer, ResolveQuery, StreamFailReason, StreamID, StreamTarget, CELL_DATA_LEN, CELL_LEN, HANDSHAKE_NTOR, TLS_HANDSHAKE_RECORD},
+    directory_protocol::{self, DirectoryRequest, DirectoryResponse, MemberAuth, NodeDescriptor, NodeFlag, NodeInfo, HEARTBEAT_INTERVAL_SECS},
 };
+use crate::config::NodeConfig;
+use crate::exit_policy::{ExitAction, ExitPolicy};
+use crate::flow::{self, DeliveryCounter, PackageWindow, StreamQueue};
+use crate::link::{CircId, DestroyReason, IncomingCircuit, Link, LinkCell, LinkCommand, LinkManager};
+use crate::tls_client::{self, ClientIdentity};
 use std::collections::HashMap;
 use std::error::Error;
-use std::net::ToSocketAddrs;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use rsa::RsaPrivateKey;
 use tokio::sync::mpsc;
-use crate::tls_client;
+use tokio::task::AbortHandle;
+use tokio_rustls::TlsAcceptor;
+use x25519_dalek::StaticSecret;
 
-pub async fn run(listen_addr: &str, key_file: &str, directory_server: Option<&str>, directory_secret: Option<&str>, ca_cert_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+struct NodeKeys {
+    identity: RsaPrivateKey,
+    onion: StaticSecret,
+    node_id: [u8; 32],
+}
+
+pub async fn run(node_config: &NodeConfig, key_passphrase: Option<&str>, rotate_key: bool, directory_server: Option<&str>, ca_cert_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let listen_addr = node_config.listen_addr.as_str();
+    let key_file = node_config.key_file.as_str();
     println!("[NODE] Starting on {}...", listen_addr);
 
-    let private_key = crypto::generate_rsa_keys();
+    let private_key = if rotate_key {
+        let new_key = crypto::generate_rsa_keys();
+        crypto::save_private_key(&new_key, key_file, key_passphrase)?;
+        println!("[NODE] Rotated identity key in {}. The node will re-register with the new key.", key_file);
+        new_key
+    } else {
+        crypto::load_or_create_identity_key(key_file, key_passphrase)?
+    };
     let public_key = private_key.to_public_key();
     crypto::save_public_key(&public_key, &format!("{}.pub", key_file));
     println!("[NODE] Public key saved to {}.pub", key_file);
 
-    if let Some(dir_addr) = directory_server {
-        let secret = directory_secret.ok_or("Directory server specified, but --directory-secret is missing")?;
-        let ca_path = ca_cert_path.ok_or("Directory server specified, but --ca-cert is missing")?;
+    let keys = Arc::new(NodeKeys {
+        node_id: crypto::node_id(&public_key),
+        onion: crypto::generate_onion_key(),
+        identity: private_key,
+    });
+
+    let flags: Vec<NodeFlag> = node_config.flags.iter().map(|flag| NodeFlag::parse(flag)).collect();
+    let exit_policy = if flags.contains(&NodeFlag::Exit) {
+        let policy = ExitPolicy::from_config(&node_config.exit_policy, node_config.exit_reject_private)?;
+        println!("[NODE] Exit policy: {}", policy);
+        policy
+    } else {
+        println!("[NODE] Not an exit; streams asking this node to connect out are refused.");
+        ExitPolicy::reject_all()
+    };
+    let exit_policy = Arc::new(exit_policy);
+
+    let listener = TcpListener::bind(listen_addr).await?;
 
+    // The member certificate also authenticates this node on its links to other relays.
+    let ca_path = ca_cert_path.ok_or("--ca-cert is missing; links between relays are authenticated with the team CA")?;
+    let member_key = crypto::load_or_create_identity_key(&node_config.member_key_file, key_passphrase)?;
+    let identity = ClientIdentity::load(&node_config.member_cert_file, &member_key)?;
+    let acceptor = TlsAcceptor::from(Arc::new(tls_client::link_server_config(ca_path, &identity)?));
+    let links = Arc::new(LinkManager::new(tls_client::link_client_config(ca_path, Some(&identity))?));
+
+    if let Some(dir_addr) = directory_server {
         println!("[NODE] Registering securely with Directory Authority at {}...", dir_addr);
         
-        let node_addr_str = format!("{}:{}", "127.0.0.1", listen_addr.split(':').last().unwrap());
-        let mut addrs_iter = node_addr_str.to_socket_addrs()?;
-        let node_addr = addrs_iter.next().ok_or("Could not resolve node address")?;
+        let node_addr = advertised_address(listen_addr, &node_config.advertise_addr)?;
+        println!("[NODE] Advertising this node at {}.", node_addr);
 
-        let node_info = NodeInfo {
+        let registration = Registration {
+            dir_addr: dir_addr.to_string(),
+            ca_path: ca_path.to_string(),
+            member_key,
+            identity: identity.clone(),
             address: node_addr,
-            public_key: public_key.clone(),
-        };
-        
-        let request = DirectoryRequest::Register {
-            info: node_info,
-            secret: secret.to_string(),
+            bandwidth: node_config.bandwidth,
+            exit_policy: (*exit_policy).clone(),
+            flags,
         };
+        register(&registration, &keys).await?;
+        tokio::spawn(send_heartbeats(registration, keys.clone()));
+    }
 
-        let mut stream = tls_client::connect(dir_addr, ca_path).await?;
+    // Every circuit a neighbor opens on any link, kept in that link's circuit table.
+    let (incoming_tx, mut incoming_rx) = mpsc::channel::<IncomingCircuit>(128);
+    let circuit_keys = keys.clone();
+    let circuit_exit_policy = exit_policy.clone();
+    tokio::spawn(async move {
+        while let Some(circuit) = incoming_rx.recv().await {
+            let keys = circuit_keys.clone();
+            let exit_policy = circuit_exit_policy.clone();
+            let links = links.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_circuit(circuit, keys, exit_policy, links).await {
+                    eprintln!("[NODE] Circuit error: {}", e);
+                }
+            });
+        }
+    });
 
-        let req_bytes = bincode::serialize(&request)?;
+    println!("[NODE] Listening for links...");
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+        let incoming = incoming_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer, acceptor, incoming).await {
+                eprintln!("[NODE] Connection error: {}", e);
+            }
+        });
+    }
+}
 
-        stream.write_u32(req_bytes.len() as u32).await?;
-        stream.write_all(&req_bytes).await?;
-        
-        let res_len = stream.read_u32().await?;
-        let mut res_buf = vec![0; res_len as usize];
-        stream.read_exact(&mut res_buf).await?;
+/// Everything the node needs to (re-)register with the directory and keep its entry alive.
+struct Registration {
+    dir_addr: String,
+    ca_path: String,
+    member_key: RsaPrivateKey,
+    identity: ClientIdentity,
+    address: SocketAddr,
+    bandwidth: u64,
+    exit_policy: ExitPolicy,
+    flags: Vec<NodeFlag>,
+}
 
-        let response: DirectoryResponse = bincode::deserialize(&res_buf)?;
-        if let DirectoryResponse::Ack = response {
-            println!("[NODE] Successfully registered with Directory Authority.");
-        } else {
-            eprintln!("[NODE] Failed to register with Directory Authority.");
+async fn register(registration: &Registration, keys: &NodeKeys) -> Result<(), Box<dyn Error>> {
+    let node_info = NodeInfo {
+        address: registration.address,
+        public_key: keys.identity.to_public_key(),
+        onion_key: Some(crypto::onion_public_key(&keys.onion)),
+        bandwidth: registration.bandwidth,
+        published: directory_protocol::unix_now(),
+        exit_policy: registration.exit_policy.clone(),
+        flags: registration.flags.clone(),
+        protocol: ProtocolSupport::ours(),
+    };
+    let descriptor = NodeDescriptor::sign(node_info, &keys.identity)?;
+    let auth = MemberAuth::sign(&registration.member_key, &descriptor.signature)?;
+    let request = DirectoryRequest::Register {
+        descriptor: Box::new(descriptor),
+        auth,
+    };
+
+    let response = directory_protocol::send_request(&registration.dir_addr, &registration.ca_path, Some(&registration.identity), &request).await?;
+    match response {
+        DirectoryResponse::Ack => println!("[NODE] Successfully registered with Directory Authority."),
+        DirectoryResponse::Denied(reason) => {
+            eprintln!("[NODE] Directory Authority refused the registration: {}", reason);
+            eprintln!("[NODE] If this member key is not enrolled yet, run 'giralnet enroll <invite>'.");
         }
+        _ => eprintln!("[NODE] Failed to register with Directory Authority."),
     }
+    Ok(())
+}
 
-    let listener = TcpListener::bind(listen_addr).await?;
-    println!("[NODE] Listening for circuits...");
+/// Keeps the node's directory entry alive, registering again whenever the directory has
+/// forgotten about it (for instance after the directory restarted).
+async fn send_heartbeats(registration: Registration, keys: Arc<NodeKeys>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
+    interval.tick().await;
     loop {
-        let (stream, _) = listener.accept().await?;
-        let private_key_clone = private_key.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, private_key_clone).await {
-                eprintln!("[NODE] Connection error: {}", e);
+        interval.tick().await;
+        if let Err(e) = send_heartbeat(&registration, &keys).await {
+            eprintln!("[NODE] Heartbeat to Directory Authority failed: {}", e);
+        }
+    }
+}
+
+async fn send_heartbeat(registration: &Registration, keys: &NodeKeys) -> Result<(), Box<dyn Error>> {
+    let timestamp = directory_protocol::unix_now();
+    let payload = directory_protocol::heartbeat_payload(registration.address, timestamp)?;
+    let request = DirectoryRequest::Heartbeat {
+        address: registration.address,
+        timestamp,
+        signature: crypto::sign(&keys.identity, &payload),
+    };
+
+    let response = directory_protocol::send_request(&registration.dir_addr, &registration.ca_path, Some(&registration.identity), &request).await?;
+    match response {
+        DirectoryResponse::UnknownNode => {
+            println!("[NODE] Directory Authority no longer knows this node. Registering again...");
+            register(registration, keys).await
+        }
+        DirectoryResponse::Denied(reason) => Err(reason.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The address registered with the directory: `advertise_addr` if set, otherwise the
+/// address the node listens on. Path selection keeps hops in different networks by these
+/// addresses, so a wildcard listen address has to be given a real one to advertise.
+fn advertised_address(listen_addr: &str, advertise_addr: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    let address = if advertise_addr.is_empty() { listen_addr } else { advertise_addr };
+    let address = address.to_socket_addrs()?.next().ok_or("Could not resolve the node's address")?;
+    if address.ip().is_unspecified() {
+        return Err(format!("{} is not an address other nodes can reach; set advertise_addr under [node]", address).into());
+    }
+    Ok(address)
+}
+
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, acceptor: TlsAcceptor, incoming: mpsc::Sender<IncomingCircuit>) -> Result<(), Box<dyn Error>> {
+    // Reachability probes from the directory connect and close without sending anything.
+    let mut first_byte = [0u8; 1];
+    let peeked = tokio::time::timeout(protocol::HANDSHAKE_TIMEOUT, stream.peek(&mut first_byte)).await
+        .map_err(|_| "Timed out waiting for the connection to start")??;
+    if peeked == 0 {
+        return Ok(());
+    }
+    match first_byte[0] {
+        TLS_HANDSHAKE_RECORD => {
+            let mut tls_stream = tokio::time::timeout(protocol::HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                .map_err(|_| "Timed out during the TLS handshake")??;
+            let peer_member = tls_client::peer_member(tls_stream.get_ref().1.peer_certificates())?;
+            let protocol = protocol::server_hello(&mut tls_stream, &format!("the link from {}", peer)).await?;
+            match &peer_member {
+                Some(member) => println!("[NODE] Link from relay {} (member {}, protocol {})", peer, member, protocol),
+                None => println!("[NODE] Link from proxy {} (protocol {})", peer, protocol),
             }
-        });
+            Link::start(tls_stream, peer, peer_member, protocol, Some(incoming));
+            Ok(())
+        }
+        HANDSHAKE_NTOR => Err("Refusing an ntor circuit without a link connection; the proxy needs upgrading".into()),
+        // Circuits only arrive over links, which is where peers are authenticated.
+        other => Err(format!("Refusing a connection that starts with {:#04x} instead of a TLS handshake; the peer may need upgrading", other).into()),
     }
 }
 
-async fn handle_connection(mut prev_hop_stream: TcpStream, private_key: RsaPrivateKey) -> Result<(), Box<dyn Error>> {
-    let handshake_len = prev_hop_stream.read_u32().await?;
-    let mut handshake_buf = vec![0; handshake_len as usize];
-    prev_hop_stream.read_exact(&mut handshake_buf).await?;
-
-    let handshake: HandshakeMessage = bincode::deserialize(&handshake_buf)?;
-    let aes_key_bytes = crypto::rsa_decrypt(&private_key, &handshake.encrypted_aes_key);
-    let session_key: [u8; 32] = aes_key_bytes.try_into()
-        .map_err(|_| "Failed to convert session key to the correct size")?;
-    println!("[NODE] Handshake successful.");
-
-    let onion_len = prev_hop_stream.read_u32().await?;
-    let mut onion_buf = vec![0; onion_len as usize];
-    prev_hop_stream.read_exact(&mut onion_buf).await?;
-    let (ciphertext, nonce) = onion_buf.split_at(onion_buf.len() - 12);
-    let decrypted_payload = crypto::aes_decrypt(&session_key, nonce, ciphertext);
-    let onion_layer: OnionLayer = bincode::deserialize(&decrypted_payload)?;
-
-    match onion_layer {
-        OnionLayer::Relay { next_hop, payload } => {
-            println!("[NODE] Peeling onion. Forwarding to {}", next_hop);
-            let mut next_stream = TcpStream::connect(next_hop).await?;
-            next_stream.write_all(&payload).await?;
-            println!("[NODE] Forwarded payload to next hop.");
-            
-            io::copy_bidirectional(&mut prev_hop_stream, &mut next_stream).await?;
-        }
-        OnionLayer::Exit => {
-            println!("[NODE] >>> EXIT NODE REACHED <<<");
-            
-            let (mut reader, mut writer) = prev_hop_stream.into_split();
-            let (tx, mut rx) = mpsc::channel::<CircuitMessage>(128);
+/// Answers the handshake in a `Create` cell, returning the reply and the keys shared with
+/// the proxy.
+fn answer_create(keys: &NodeKeys, payload: &[u8]) -> Result<([u8; CELL_LEN], crypto::RelayCrypto), Box<dyn Error>> {
+    let handshake: NtorHandshake = protocol::decode(payload, MessageKind::Cell)?;
+    if handshake.node_id != keys.node_id {
+        return Err("ntor handshake is addressed to a different node".into());
+    }
+    let crypto::NtorServerReply { server_key, auth, relay } =
+        crypto::ntor_server_respond(&keys.onion, &keys.node_id, &handshake.client_key)?;
+    let reply_bytes = bincode::serialize(&NtorReply { server_key, auth })?;
+    Ok((protocol::pad_to_cell(&reply_bytes)?, relay))
+}
 
-            tokio::spawn(async move {
-                while let Some(msg) = rx.recv().await {
-                    let Ok(bytes) = bincode::serialize(&msg) else { continue };
-                    let Ok(_) = writer.write_u32(bytes.len() as u32).await else { break };
-                    if writer.write_all(&bytes).await.is_err() {
-                        break;
+/// How long a hop waits for a link to the next hop and the next hop's answer to the
+/// handshake it passes on.
+const EXTEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves one circuit a neighbor opened on a link. Relay cells this hop recognizes are for
+/// it: `Extend` opens a circuit to a next hop, and stream commands make it act as the
+/// exit. Every other relay cell is passed on to the next hop, and the next hop's cells are
+/// passed back with this hop's layer added. A `Destroy` from either side, or losing either
+/// link, tears the circuit down on both sides.
+async fn serve_circuit(circuit: IncomingCircuit, keys: Arc<NodeKeys>, exit_policy: Arc<ExitPolicy>, links: Arc<LinkManager>) -> Result<(), Box<dyn Error>> {
+    let IncomingCircuit { link: prev, circ_id, cells: mut prev_cells } = circuit;
+    let Some(create) = prev_cells.recv().await else { return Ok(()) };
+    let answered = answer_create(&keys, &create.payload).map_err(|e| e.to_string());
+    let (reply_cell, relay) = match answered {
+        Ok(answered) => answered,
+        Err(e) => {
+            prev.destroy_circuit(circ_id, DestroyReason::Protocol).await;
+            return Err(e.into());
+        }
+    };
+    prev.send(LinkCell::new(circ_id, LinkCommand::Created, reply_cell)).await?;
+    println!("[NODE] ntor handshake successful for circuit {} from {}.", circ_id, prev.peer);
+
+    let crypto::RelayCrypto { mut forward, mut backward } = relay;
+    let (tx, mut rx) = mpsc::channel::<CircuitMessage>(128);
+    let (relayed_tx, mut relayed_rx) = mpsc::channel::<LinkCell>(128);
+    // Why the circuit is torn down. Whichever side goes first decides the reason passed on
+    // to the other.
+    let teardown = Arc::new(OnceLock::<DestroyReason>::new());
+
+    // The only task adding the backward layer, so our own cells and the ones relayed from
+    // the next hop go through the keystream in the order they are sent. Once the next hop
+    // goes away, the previous hop is told to tear the circuit down too.
+    let writer_prev = prev.clone();
+    let writer_teardown = teardown.clone();
+    tokio::spawn(async move {
+        let reason = loop {
+            let cells = tokio::select! {
+                // Only ends once the circuit is torn down from the previous hop's side.
+                msg = rx.recv() => match msg {
+                    Some(msg) => seal_cells(&mut backward, msg),
+                    None => return,
+                },
+                cell = relayed_rx.recv() => match cell {
+                    Some(cell) if cell.command == LinkCommand::Relay => {
+                        let mut payload = cell.payload;
+                        backward.apply(&mut payload);
+                        vec![payload]
                     }
+                    Some(cell) if cell.command == LinkCommand::Destroy => break cell.destroy_reason(),
+                    Some(_) => break DestroyReason::Protocol,
+                    None => break DestroyReason::ConnectionLost,
+                },
+            };
+            for cell in cells {
+                if writer_prev.send(LinkCell::new(circ_id, LinkCommand::Relay, cell)).await.is_err() {
+                    return;
                 }
-            });
+            }
+        };
+        let reason = *writer_teardown.get_or_init(|| reason);
+        writer_prev.destroy_circuit(circ_id, reason).await;
+    });
+
+    let mut relayed_tx = Some(relayed_tx);
+    let mut next: Option<(Arc<Link>, CircId)> = None;
+    let mut streams = ExitStreams::new(tx.clone(), exit_policy);
+    let mut result = Ok(());
+    let reason = loop {
+        let Some(cell) = prev_cells.recv().await else { break DestroyReason::ConnectionLost };
+        match cell.command {
+            LinkCommand::Relay => {}
+            LinkCommand::Destroy => {
+                prev.remove_circuit(circ_id);
+                break cell.destroy_reason();
+            }
+            command => {
+                result = Err(format!("Received an unexpected {:?} cell on an open circuit", command));
+                break DestroyReason::Protocol;
+            }
+        }
+        let mut payload = cell.payload;
+        forward.apply(&mut payload);
+        if !forward.recognize(&payload) {
+            let Some((next_link, next_id)) = &next else {
+                result = Err("Received a relay cell that failed integrity checks at the last hop".to_string());
+                break DestroyReason::Protocol;
+            };
+            if next_link.send(LinkCell::new(*next_id, LinkCommand::Relay, payload)).await.is_err() {
+                break DestroyReason::ConnectionLost;
+            }
+            continue;
+        }
 
-            let mut target_streams = HashMap::<StreamID, mpsc::Sender<Vec<u8>>>::new();
-            loop {
-                let msg_len = match reader.read_u32().await {
-                    Ok(len) => len,
-                    Err(_) => break,
+        let msg = match Cell::decode(&payload).and_then(CircuitMessage::try_from) {
+            Ok(msg) => msg,
+            Err(e) => {
+                result = Err(e.to_string());
+                break DestroyReason::Protocol;
+            }
+        };
+        match msg {
+            CircuitMessage::Extend { next_hop, operator, handshake } => {
+                let reply = match relayed_tx.take() {
+                    None => CircuitMessage::ExtendFailed { reason: ExtendFailReason::AlreadyExtended },
+                    Some(relayed) => {
+                        println!("[NODE] Extending circuit to {}", next_hop);
+                        match extend(&links, &next_hop, &operator, &handshake).await {
+                            Ok((next_link, next_id, mut next_cells, reply)) => {
+                                // Hands the next hop's cells to the writer, up to and
+                                // including a Destroy.
+                                let forwarder_link = next_link.clone();
+                                tokio::spawn(async move {
+                                    let protocol_error = loop {
+                                        let Some(cell) = next_cells.recv().await else { break false };
+                                        let command = cell.command;
+                                        // The circuit is already being torn down from the previous hop's side.
+                                        if relayed.send(cell).await.is_err() {
+                                            return;
+                                        }
+                                        if command != LinkCommand::Relay {
+                                            break command != LinkCommand::Destroy;
+                                        }
+                                    };
+                                    if protocol_error {
+                                        forwarder_link.destroy_circuit(next_id, DestroyReason::Protocol).await;
+                                    } else {
+                                        forwarder_link.remove_circuit(next_id);
+                                    }
+                                });
+                                next = Some((next_link, next_id));
+                                CircuitMessage::Extended { reply }
+                            }
+                            Err(reason) => {
+                                eprintln!("[NODE] Could not extend circuit to {}: {}", next_hop, reason);
+                                relayed_tx = Some(relayed);
+                                CircuitMessage::ExtendFailed { reason }
+                            }
+                        }
+                    }
                 };
-                let mut msg_buf = vec![0; msg_len as usize];
-                if reader.read_exact(&mut msg_buf).await.is_err() {
-                    break;
+                if tx.send(reply).await.is_err() {
+                    break DestroyReason::ConnectionLost;
+                }
+            }
+            msg => {
+                if let Err(e) = streams.handle(msg).await {
+                    result = Err(e);
+                    break DestroyReason::Protocol;
                 }
+            }
+        }
+    };
+    let reason = *teardown.get_or_init(|| reason);
+    println!("[NODE] Circuit {} from {} torn down: {}.", circ_id, prev.peer, reason);
+    // Ends the streams this node opened as the circuit's exit.
+    drop(streams);
+    prev.destroy_circuit(circ_id, reason).await;
+    if let Some((next_link, next_id)) = next {
+        next_link.destroy_circuit(next_id, reason).await;
+    }
+    result.map_err(Into::into)
+}
 
-                let circuit_msg: CircuitMessage = match bincode::deserialize(&msg_buf) {
-                    Ok(msg) => msg,
-                    Err(_) => continue,
-                };
+/// Opens a circuit to the next hop over the link to it, passing on the proxy's handshake,
+/// and returns the circuit together with the next hop's reply.
+async fn extend(links: &LinkManager, next_hop: &str, operator: &str, handshake: &NtorHandshake) -> Result<(Arc<Link>, CircId, mpsc::Receiver<LinkCell>, NtorReply), ExtendFailReason> {
+    let deadline = tokio::time::Instant::now() + EXTEND_TIMEOUT;
+    let addr: SocketAddr = next_hop.parse().map_err(|_| ExtendFailReason::Unreachable)?;
+    let payload = bincode::serialize(handshake).ok()
+        .and_then(|bytes| protocol::pad_to_cell(&bytes).ok())
+        .ok_or(ExtendFailReason::HandshakeFailed)?;
+    let link = match tokio::time::timeout_at(deadline, links.get(addr, operator)).await {
+        Ok(Ok(link)) => link,
+        Ok(Err(e)) => {
+            eprintln!("[NODE] No link to {}: {}", addr, e);
+            return Err(ExtendFailReason::Unreachable);
+        }
+        Err(_) => return Err(ExtendFailReason::TimedOut),
+    };
 
-                match circuit_msg {
-                    CircuitMessage::BeginStream { id, destination } => {
-                        println!("[EXIT] New stream {} to {}", id, destination);
-                        let tx_clone = tx.clone();
-                        let (target_tx, mut target_rx) = mpsc::channel::<Vec<u8>>(128);
-                        target_streams.insert(id, target_tx);
-
-                        tokio::spawn(async move {
-                            let target_stream = match TcpStream::connect(destination).await {
-                                Ok(stream) => stream,
-                                Err(e) => {
-                                    eprintln!("[EXIT] Failed to connect to {}: {}", destination, e);
-                                    let _ = tx_clone.send(CircuitMessage::EndStream { id }).await;
-                                    return;
-                                }
-                            };
-                            let (mut target_reader, mut target_writer) = target_stream.into_split();
+    let (next_id, mut next_cells) = link.add_circuit().ok_or(ExtendFailReason::Unreachable)?;
+    let created = async {
+        link.send(LinkCell::new(next_id, LinkCommand::Create, payload)).await.map_err(|_| ExtendFailReason::HandshakeFailed)?;
+        match next_cells.recv().await {
+            Some(cell) if cell.command == LinkCommand::Created => {
+                protocol::decode::<NtorReply>(&cell.payload, MessageKind::Cell).map_err(|_| ExtendFailReason::HandshakeFailed)
+            }
+            _ => Err(ExtendFailReason::HandshakeFailed),
+        }
+    };
+    match tokio::time::timeout_at(deadline, created).await.unwrap_or(Err(ExtendFailReason::TimedOut)) {
+        Ok(reply) => Ok((link, next_id, next_cells, reply)),
+        Err(reason) => {
+            link.destroy_circuit(next_id, DestroyReason::Requested).await;
+            Err(reason)
+        }
+    }
+}
 
-                            let forward_task = tokio::spawn(async move {
-                                while let Some(data) = target_rx.recv().await {
-                                    if target_writer.write_all(&data).await.is_err() {
-                                        break;
-                                    }
-                                }
-                            });
-                            
-                            let mut read_buf = vec![0; 4096];
-                            loop {
-                                let n = match target_reader.read(&mut read_buf).await {
-                                    Ok(n) if n > 0 => n,
-                                    _ => break,
-                                };
-                                let data = read_buf[..n].to_vec();
-                                if tx_clone.send(CircuitMessage::StreamData { id, data }).await.is_err() {
-                                    break;
-                                }
-                            }
-                            
-                            forward_task.abort();
-                            let _ = tx_clone.send(CircuitMessage::EndStream { id }).await;
-                            println!("[EXIT] Closed stream {} to {}", id, destination);
-                        });
-                    }
-                    CircuitMessage::StreamData { id, data } => {
-                        if let Some(tx) = target_streams.get(&id) {
-                            let _ = tx.send(data).await;
+/// The streams a circuit has opened through this node as its exit.
+struct ExitStreams {
+    tx: mpsc::Sender<CircuitMessage>,
+    exit_policy: Arc<ExitPolicy>,
+    target_streams: HashMap<StreamID, TargetStream>,
+    /// Room left for data cells towards the proxy, shared by all streams.
+    package_window: Arc<PackageWindow>,
+    /// Counts data cells from the proxy towards the next circuit `Sendme`.
+    delivered: DeliveryCounter,
+    /// Lookups still running, by request ID, stopped with the circuit like its streams.
+    resolves: HashMap<StreamID, AbortHandle>,
+}
+
+/// The exit's end of a stream.
+struct TargetStream {
+    /// Data from the proxy, waiting to be written to the target, and `None` once the proxy
+    /// has finished sending.
+    data: StreamQueue,
+    /// Room left for data cells towards the proxy on this stream.
+    window: Arc<PackageWindow>,
+    /// The task connecting to the target and moving the stream's data.
+    task: AbortHandle,
+}
+
+/// Dropping a stream, when the proxy ends it or the circuit is torn down, stops its task
+/// and closes the connection to the target.
+impl Drop for TargetStream {
+    fn drop(&mut self) {
+        self.window.close();
+        self.task.abort();
+    }
+}
+
+impl Drop for ExitStreams {
+    fn drop(&mut self) {
+        self.package_window.close();
+        for task in self.resolves.values() {
+            task.abort();
+        }
+    }
+}
+
+impl ExitStreams {
+    /// `tx` carries messages back towards the proxy.
+    fn new(tx: mpsc::Sender<CircuitMessage>, exit_policy: Arc<ExitPolicy>) -> Self {
+        Self {
+            tx,
+            exit_policy,
+            target_streams: HashMap::new(),
+            package_window: Arc::new(PackageWindow::circuit()),
+            delivered: DeliveryCounter::circuit(),
+            resolves: HashMap::new(),
+        }
+    }
+
+    /// Acts on a message from the proxy. Fails if the proxy broke the protocol, which
+    /// should cost it the circuit.
+    async fn handle(&mut self, msg: CircuitMessage) -> Result<(), String> {
+        match msg {
+            CircuitMessage::BeginStream { id, target, half_close } => {
+                println!("[EXIT] New stream {} to {}", id, target);
+                let tx_clone = self.tx.clone();
+                let exit_policy = self.exit_policy.clone();
+                let (target_tx, mut target_rx) = flow::stream_queue();
+                let window = Arc::new(PackageWindow::stream());
+                let circuit_window = self.package_window.clone();
+                let stream_window = window.clone();
+
+                let task = tokio::spawn(async move {
+                    let window = stream_window;
+                    let target_stream = match connect_target(&target, &exit_policy).await {
+                        Ok(stream) => stream,
+                        Err(reason) => {
+                            eprintln!("[EXIT] Failed to connect to {}: {}", target, reason);
+                            let _ = tx_clone.send(CircuitMessage::StreamFailed { id, reason }).await;
+                            return;
                         }
+                    };
+                    if tx_clone.send(CircuitMessage::StreamConnected { id }).await.is_err() {
+                        return;
                     }
-                    CircuitMessage::EndStream { id } => {
-                        target_streams.remove(&id);
-                        println!("[EXIT] Proxy requested to end stream {}", id);
-                    }
+                    let (mut target_reader, mut target_writer) = target_stream.into_split();
+
+                    // The proxy may send more on the stream once what it sent has reached the target.
+                    let sendme_tx = tx_clone.clone();
+                    // Once the proxy has finished sending, the target is told by shutting down
+                    // the write half, and may still answer.
+                    let forward = async move {
+                        let mut delivered = DeliveryCounter::stream();
+                        while let Some(Some(data)) = target_rx.recv().await {
+                            if target_writer.write_all(&data).await.is_err() {
+                                return Err(EndReason::ConnectionReset);
+                            }
+                            if delivered.deliver() && sendme_tx.send(CircuitMessage::Sendme { id }).await.is_err() {
+                                return Err(EndReason::Done);
+                            }
+                        }
+                        let _ = target_writer.shutdown().await;
+                        Ok(())
+                    };
+
+                    // Each read fills at most one data cell, so it takes one cell of both windows.
+                    let pump_tx = tx_clone.clone();
+                    let pump = async move {
+                        let mut read_buf = vec![0; CELL_DATA_LEN];
+                        loop {
+                            let n = match target_reader.read(&mut read_buf).await {
+                                // A proxy without half-closes takes the end of the target's
+                                // answer for the end of the stream.
+                                Ok(0) if !half_close => return Err(EndReason::Done),
+                                Ok(0) => break,
+                                Ok(n) => n,
+                                Err(_) => return Err(EndReason::ConnectionReset),
+                            };
+                            // Both only fail once the circuit is going away.
+                            if !window.take().await || !circuit_window.take().await {
+                                return Err(EndReason::Done);
+                            }
+                            let data = read_buf[..n].to_vec();
+                            if pump_tx.send(CircuitMessage::StreamData { id, data }).await.is_err() {
+                                return Err(EndReason::Done);
+                            }
+                        }
+                        let _ = pump_tx.send(CircuitMessage::StreamFinished { id }).await;
+                        Ok(())
+                    };
+
+                    // Closed cleanly once both directions have finished and the proxy's data
+                    // has all been written to the target.
+                    let reason = tokio::try_join!(forward, pump).err().unwrap_or(EndReason::Done);
+                    let _ = tx_clone.send(CircuitMessage::EndStream { id, reason }).await;
+                    println!("[EXIT] Closed stream {} to {}: {}", id, target, reason);
+                });
+                self.target_streams.insert(id, TargetStream { data: target_tx, window, task: task.abort_handle() });
+            }
+            CircuitMessage::Resolve { id, query } => {
+                let tx_clone = self.tx.clone();
+                let exit_policy = self.exit_policy.clone();
+                let task = tokio::spawn(async move {
+                    let answer = resolve(&query, &exit_policy).await;
+                    println!("[EXIT] Resolved {:?} for request {}", query, id);
+                    let _ = tx_clone.send(CircuitMessage::Resolved { id, answer }).await;
+                });
+                self.resolves.retain(|_, task| !task.is_finished());
+                if let Some(earlier) = self.resolves.insert(id, task.abort_handle()) {
+                    earlier.abort();
+                }
+            }
+            CircuitMessage::StreamData { id, data } => {
+                if self.delivered.deliver() {
+                    let _ = self.tx.send(CircuitMessage::Sendme { id: 0 }).await;
                 }
+                self.queue(id, Some(data)).await?;
             }
+            CircuitMessage::StreamFinished { id } => self.queue(id, None).await?,
+            CircuitMessage::Sendme { id: 0 } => self.package_window.sendme(),
+            CircuitMessage::Sendme { id } => {
+                if let Some(stream) = self.target_streams.get(&id) {
+                    stream.window.sendme();
+                }
+            }
+            CircuitMessage::EndStream { id, reason } => {
+                if self.target_streams.remove(&id).is_some() {
+                    println!("[EXIT] Proxy ended stream {}: {}", id, reason);
+                }
+            }
+            // Only ever sent towards the proxy.
+            CircuitMessage::Resolved { .. }
+            | CircuitMessage::StreamConnected { .. }
+            | CircuitMessage::StreamFailed { .. }
+            | CircuitMessage::Extended { .. }
+            | CircuitMessage::ExtendFailed { .. } => {}
+            // Handled by the circuit before it reaches the streams.
+            CircuitMessage::Extend { .. } => {}
         }
+        Ok(())
     }
-    println!("[NODE] Connection closed.");
-    Ok(())
-}
\ No newline at end of file
+
+    /// Hands data from the proxy to a stream's task.
+    async fn queue(&self, id: StreamID, data: Option<Vec<u8>>) -> Result<(), String> {
+        let Some(stream) = self.target_streams.get(&id) else { return Ok(()) };
+        if !flow::queue(&stream.data, data) {
+            return Err(format!("The proxy overran the window of stream {}", id));
+        }
+        Ok(())
+    }
+}
+
+/// How long the exit tries to reach a stream's target before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Connects to a stream target, resolving hostnames here at the exit, and only to
+/// addresses the exit policy allows. Errors are reduced to the reason reported back to the
+/// proxy.
+async fn connect_target(target: &StreamTarget, exit_policy: &ExitPolicy) -> Result<TcpStream, StreamFailReason> {
+    let addrs: Vec<SocketAddr> = match target {
+        StreamTarget::Address(addr) => vec![*addr],
+        StreamTarget::Domain(host, port) => match tokio::net::lookup_host((host.as_str(), *port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(_) => return Err(StreamFailReason::ResolveFailed),
+        },
+    };
+    if addrs.is_empty() {
+        return Err(StreamFailReason::ResolveFailed);
+    }
+    // Checked after resolution, so a hostname pointing into a rejected range is refused too.
+    let addrs: Vec<SocketAddr> = addrs.into_iter().filter(|addr| exit_policy.allows(addr)).collect();
+    if addrs.is_empty() {
+        return Err(StreamFailReason::ExitPolicy);
+    }
+
+    let attempts = async {
+        let mut reason = StreamFailReason::Other;
+        for addr in &addrs {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => reason = fail_reason(&e),
+            }
+        }
+        Err(reason)
+    };
+    tokio::time::timeout(CONNECT_TIMEOUT, attempts).await.unwrap_or(Err(StreamFailReason::TimedOut))
+}
+
+fn fail_reason(error: &io::Error) -> StreamFailReason {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => StreamFailReason::Refused,
+        io::ErrorKind::NetworkUnreachable => StreamFailReason::NetworkUnreachable,
+        io::ErrorKind::HostUnreachable => StreamFailReason::HostUnreachable,
+        io::ErrorKind::TimedOut => StreamFailReason::TimedOut,
+        _ => StreamFailReason::Other,
+    }
+}
+
+/// Most addresses a `Resolved` answer carries, so that it always fits in a single cell.
+const MAX_RESOLVED_ADDRESSES: usize = 16;
+/// Longest name a `Resolved` answer carries: the longest a DNS name can be, which also
+/// always fits in a single cell.
+const MAX_RESOLVED_NAME_LEN: usize = 253;
+
+/// Answers a lookup the way a stream would be treated: only exits answer, with the
+/// addresses their policy would connect to, and reverse lookups only for addresses it
+/// allows. Nodes that are not exits have a policy that rejects everything.
+async fn resolve(query: &ResolveQuery, exit_policy: &ExitPolicy) -> ResolveAnswer {
+    match query {
+        ResolveQuery::Hostname(name) => {
+            if !exit_policy.rules.iter().any(|rule| rule.action == ExitAction::Accept) {
+                return ResolveAnswer::Failed;
+            }
+            let Ok(addrs) = tokio::net::lookup_host((name.as_str(), 0)).await else {
+                return ResolveAnswer::Failed;
+            };
+            let mut ips: Vec<IpAddr> = Vec::new();
+            for addr in addrs.filter(|addr| exit_policy.allows_address(addr.ip())) {
+                if !ips.contains(&addr.ip()) && ips.len() < MAX_RESOLVED_ADDRESSES {
+                    ips.push(addr.ip());
+                }
+            }
+            if ips.is_empty() {
+                ResolveAnswer::Failed
+            } else {
+                ResolveAnswer::Addresses(ips)
+            }
+        }
+        ResolveQuery::Address(ip) => {
+            let ip = *ip;
+            if !exit_policy.allows_address(ip) {
+                return ResolveAnswer::Failed;
+            }
+            match tokio::task::spawn_blocking(move || reverse_lookup(ip)).await {
+                Ok(Some(name)) => name_answer(name),
+                _ => ResolveAnswer::Failed,
+            }
+        }
+    }
+}
+
+/// Answers a reverse lookup with `name`. A longer name than DNS allows would not fit in
+/// the `Resolved` cell, so the proxy is told the lookup failed instead of never hearing back.
+fn name_answer(name: String) -> ResolveAnswer {
+    if name.len() > MAX_RESOLVED_NAME_LEN {
+        return ResolveAnswer::Failed;
+    }
+    ResolveAnswer::Hostname(name)
+}
+
+/// Looks up the PTR name of `ip` through the system resolver. Blocking.
+#[cfg(unix)]
+fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    // SAFETY: `addr` is a valid socket address of `addr.len()` bytes and `host` is a
+    // writable buffer of the length passed; no service name is requested.
+    let rc = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr() as *const libc::sockaddr,
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    // SAFETY: on success getnameinfo leaves a NUL-terminated string in `host`.
+    let name = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    name.to_str().ok().map(str::to_owned)
+}
+
+#[cfg(not(unix))]
+fn reverse_lookup(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+/// Seals a message travelling towards the proxy into cells, fragmenting stream data as
+/// needed.
+fn seal_cells(layer: &mut crypto::RelayLayer, msg: CircuitMessage) -> Vec<[u8; CELL_LEN]> {
+    let Ok(cells) = msg.into_cells() else { return Vec::new() };
+    cells.into_iter().map(|cell| {
+        let mut bytes = cell.encode();
+        layer.seal(&mut bytes);
+        layer.apply(&mut bytes);
+        bytes
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::{CIRCUIT_WINDOW, STREAM_WINDOW};
+
+    fn accept_all() -> Arc<ExitPolicy> {
+        Arc::new(ExitPolicy::from_config(&["accept *:*".to_string()], false).unwrap())
+    }
+
+    /// A target that sends to everyone connecting as fast as they read.
+    async fn flood_target() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let chunk = [7u8; 4096];
+                    while stream.write_all(&chunk).await.is_ok() {}
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_does_not_block_the_circuit() {
+        let target = StreamTarget::Address(flood_target().await);
+        let (tx, mut rx) = mpsc::channel(128);
+        let mut streams = ExitStreams::new(tx, accept_all());
+        streams.handle(CircuitMessage::BeginStream { id: 1, target: target.clone(), half_close: true }).await.unwrap();
+        streams.handle(CircuitMessage::BeginStream { id: 2, target, half_close: true }).await.unwrap();
+
+        // Plays a proxy whose browser stopped reading stream 1: the circuit and stream 2
+        // are acknowledged as cells arrive, stream 1 never is.
+        let mut circuit_delivered = DeliveryCounter::circuit();
+        let mut stream_delivered = DeliveryCounter::stream();
+        let mut cells = HashMap::<StreamID, usize>::new();
+        while cells.get(&2).copied().unwrap_or(0) < 3 * STREAM_WINDOW {
+            let msg = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await
+                .expect("stream 2 stopped receiving data")
+                .unwrap();
+            let CircuitMessage::StreamData { id, .. } = msg else { continue };
+            *cells.entry(id).or_default() += 1;
+            if circuit_delivered.deliver() {
+                streams.handle(CircuitMessage::Sendme { id: 0 }).await.unwrap();
+            }
+            if id == 2 && stream_delivered.deliver() {
+                streams.handle(CircuitMessage::Sendme { id: 2 }).await.unwrap();
+            }
+        }
+        assert!(cells[&1] <= STREAM_WINDOW);
+        assert!(cells[&2] > CIRCUIT_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn proxy_overrunning_a_stream_window_loses_the_circuit() {
+        let target = StreamTarget::Address(flood_target().await);
+        let (tx, _rx) = mpsc::channel(128);
+        let mut streams = ExitStreams::new(tx, accept_all());
+        streams.handle(CircuitMessage::BeginStream { id: 1, target, half_close: true }).await.unwrap();
+
+        // Nothing is written to the target before the stream's task gets to run, so every
+        // cell stays queued. The queue holds the window and the end marker.
+        for _ in 0..=STREAM_WINDOW {
+            streams.handle(CircuitMessage::StreamData { id: 1, data: vec![0; CELL_DATA_LEN] }).await.unwrap();
+        }
+        let overrun = streams.handle(CircuitMessage::StreamData { id: 1, data: vec![0; CELL_DATA_LEN] }).await;
+        assert!(overrun.is_err());
+    }
+
+    #[tokio::test]
+    async fn exit_rejecting_private_ranges_does_not_reach_this_host() {
+        let target = flood_target().await;
+        let no_private = ExitPolicy::from_config(&["accept *:*".to_string()], true).unwrap();
+        for target in [StreamTarget::Address(target), StreamTarget::Domain("localhost".to_string(), target.port())] {
+            assert!(matches!(connect_target(&target, &no_private).await, Err(StreamFailReason::ExitPolicy)), "{}", target);
+        }
+        assert!(connect_target(&StreamTarget::Address(target), &accept_all()).await.is_ok());
+    }
+
+    #[test]
+    fn nodes_advertise_the_address_they_listen_on() {
+        assert_eq!(advertised_address("192.0.2.7:9001", "").unwrap(), "192.0.2.7:9001".parse().unwrap());
+        assert_eq!(advertised_address("0.0.0.0:9001", "198.51.100.1:443").unwrap(), "198.51.100.1:443".parse().unwrap());
+        assert!(advertised_address("0.0.0.0:9001", "").is_err());
+        assert!(advertised_address("[::]:9001", "").is_err());
+    }
+
+    /// Sends a lookup through an exit's streams and waits for its answer.
+    async fn resolve_through(exit_policy: ExitPolicy, query: ResolveQuery) -> ResolveAnswer {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut streams = ExitStreams::new(tx, Arc::new(exit_policy));
+        streams.handle(CircuitMessage::Resolve { id: 3, query }).await.unwrap();
+        match rx.recv().await {
+            Some(CircuitMessage::Resolved { id: 3, answer }) => answer,
+            other => panic!("expected an answer, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_exits_answer_lookups_their_policy_allows() {
+        let localhost = || ResolveQuery::Hostname("localhost".to_string());
+        let loopback = || ResolveQuery::Address("127.0.0.1".parse().unwrap());
+        assert!(matches!(resolve_through((*accept_all()).clone(), localhost()).await, ResolveAnswer::Addresses(_)));
+
+        let no_private = ExitPolicy::from_config(&["accept *:*".to_string()], true).unwrap();
+        for policy in [ExitPolicy::reject_all(), no_private] {
+            assert!(matches!(resolve_through(policy.clone(), localhost()).await, ResolveAnswer::Failed));
+            assert!(matches!(resolve_through(policy, loopback()).await, ResolveAnswer::Failed));
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_stop_with_the_circuit() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut streams = ExitStreams::new(tx, accept_all());
+        streams.handle(CircuitMessage::Resolve { id: 3, query: ResolveQuery::Hostname("localhost".to_string()) }).await.unwrap();
+        drop(streams);
+        // The aborted lookup never answers, and the channel closes once its task is gone.
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn oversized_lookup_answers_are_failures_that_fit_in_a_cell() {
+        let fits = |answer: ResolveAnswer| CircuitMessage::Resolved { id: 3, answer }.into_cells().is_ok_and(|cells| cells.len() == 1);
+        let longest = "a".repeat(MAX_RESOLVED_NAME_LEN);
+        assert!(matches!(name_answer(longest.clone()), ResolveAnswer::Hostname(ref name) if *name == longest));
+        assert!(fits(name_answer(longest)));
+        // The system resolver can hand back up to NI_MAXHOST bytes, which would not fit.
+        let oversized = "a".repeat(1024);
+        assert!(!fits(ResolveAnswer::Hostname(oversized.clone())));
+        assert!(matches!(name_answer(oversized), ResolveAnswer::Failed));
+        assert!(fits(ResolveAnswer::Failed));
+
+        let ipv6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(fits(ResolveAnswer::Addresses(vec![ipv6; MAX_RESOLVED_ADDRESSES])));
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! Choosing the nodes of a circuit: a guard first, middles, and an exit whose policy
+//! accepts the target, with no two hops run by the same operator or sitting in the same
+//! network.
+
+use rand::seq::SliceRandom;
+use std::error::Error;
+use std::net::IpAddr;
+
+use crate::crypto;
+use crate::directory_protocol::{NodeFlag, NodeInfo};
+use crate::protocol::StreamTarget;
+
+/// A node from the consensus, with the operator the directory vouches for.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub info: NodeInfo,
+    pub operator: String,
+}
+
+pub struct PathConstraints {
+    /// Refuse two hops in the same /16 (IPv4) or /32 (IPv6) network.
+    pub distinct_subnets: bool,
+}
+
+/// Picks `circuit_len` nodes, entry first. The entry is the first of `entry_guards` (node
+/// IDs in order of preference) that fits, and the exit accepts `target`, or when there is
+/// no target yet, preferably HTTPS.
+pub fn select_path(candidates: &[Candidate], entry_guards: &[[u8; 32]], target: Option<&StreamTarget>, circuit_len: usize, constraints: &PathConstraints) -> Result<Vec<Candidate>, Box<dyn Error>> {
+    if circuit_len == 0 {
+        return Err("A circuit needs at least one hop".into());
+    }
+    if entry_guards.is_empty() {
+        return Err("No usable entry guards".into());
+    }
+    let mut rng = rand::thread_rng();
+    let guards: Vec<&Candidate> = entry_guards.iter()
+        .filter_map(|id| candidates.iter().find(|c| c.info.has_flag(&NodeFlag::Guard) && &crypto::node_id(&c.info.public_key) == id))
+        .collect();
+
+    let exits: Vec<&Candidate> = candidates.iter().filter(|c| c.info.has_flag(&NodeFlag::Exit)).collect();
+    let mut usable: Vec<&Candidate> = match target {
+        Some(target) => exits.iter().copied().filter(|c| c.info.exit_policy.allows_target(target)).collect(),
+        None => exits.iter().copied().filter(|c| c.info.exit_policy.allows_port(443)).collect(),
+    };
+    if usable.is_empty() {
+        match target {
+            Some(target) => return Err(format!("No exit's policy allows {}", target).into()),
+            None => usable = exits,
+        }
+    }
+    usable.shuffle(&mut rng);
+
+    // Try exits in random order, since the first one may leave no compatible guard or
+    // middles in a small network.
+    for exit in usable {
+        if circuit_len == 1 {
+            if guards.iter().any(|guard| guard.info.address == exit.info.address) {
+                return Ok(vec![exit.clone()]);
+            }
+            continue;
+        }
+        for guard in guards.iter().copied().filter(|guard| compatible(guard, &[exit], constraints)) {
+            let mut path = vec![guard, exit];
+            let mut middles: Vec<&Candidate> = candidates.iter().filter(|c| compatible(c, &path, constraints)).collect();
+            middles.shuffle(&mut rng);
+            for middle in middles {
+                if path.len() == circuit_len {
+                    break;
+                }
+                if compatible(middle, &path, constraints) {
+                    path.insert(path.len() - 1, middle);
+                }
+            }
+            if path.len() == circuit_len {
+                return Ok(path.into_iter().cloned().collect());
+            }
+        }
+    }
+    Err(format!(
+        "Could not find {} nodes for a circuit: it needs one of our entry guards, an Exit{}, and hops run by different operators{}",
+        circuit_len,
+        target.map(|t| format!(" that allows {}", t)).unwrap_or_default(),
+        if constraints.distinct_subnets { " in different networks" } else { "" },
+    ).into())
+}
+
+/// Whether `candidate` may join a path already holding `path`.
+fn compatible(candidate: &Candidate, path: &[&Candidate], constraints: &PathConstraints) -> bool {
+    path.iter().all(|hop| {
+        hop.info.address != candidate.info.address
+            && hop.operator != candidate.operator
+            && !(constraints.distinct_subnets && same_subnet(hop.info.address.ip(), candidate.info.address.ip()))
+    })
+}
+
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..2] == b.octets()[..2],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.octets()[..4] == b.octets()[..4],
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_policy::ExitPolicy;
+    use crate::protocol::ProtocolSupport;
+    use rsa::RsaPrivateKey;
+
+    fn node(address: &str, operator: &str, flags: &[NodeFlag], exit_policy: &[&str]) -> Candidate {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        let rules: Vec<String> = exit_policy.iter().map(|rule| rule.to_string()).collect();
+        Candidate {
+            info: NodeInfo {
+                address: address.parse().unwrap(),
+                public_key: key.to_public_key(),
+                onion_key: Some([0; 32]),
+                bandwidth: 0,
+                published: 0,
+                exit_policy: ExitPolicy::from_config(&rules, false).unwrap(),
+                flags: flags.to_vec(),
+                protocol: ProtocolSupport::ours(),
+            },
+            operator: operator.to_string(),
+        }
+    }
+
+    fn id(candidate: &Candidate) -> [u8; 32] {
+        crypto::node_id(&candidate.info.public_key)
+    }
+
+    fn addresses(path: &[Candidate]) -> Vec<String> {
+        path.iter().map(|hop| hop.info.address.to_string()).collect()
+    }
+
+    const ANY_SUBNET: PathConstraints = PathConstraints { distinct_subnets: false };
+
+    fn web() -> StreamTarget {
+        StreamTarget::Address("192.0.2.7:80".parse().unwrap())
+    }
+
+    #[test]
+    fn hops_take_the_roles_their_flags_allow() {
+        // The middle accepts everything, but without the Exit flag it never ends a path.
+        let guard = node("10.0.0.1:9001", "alice", &[NodeFlag::Guard], &[]);
+        let middle = node("10.1.0.1:9001", "bob", &[], &["accept *:*"]);
+        let exit = node("10.2.0.1:9001", "carol", &[NodeFlag::Exit], &["accept *:80"]);
+        let nodes = vec![middle, exit, guard];
+        for _ in 0..10 {
+            let path = select_path(&nodes, &[id(&nodes[2])], Some(&web()), 3, &ANY_SUBNET).unwrap();
+            assert_eq!(addresses(&path), ["10.0.0.1:9001", "10.1.0.1:9001", "10.2.0.1:9001"]);
+        }
+    }
+
+    #[test]
+    fn entry_must_be_a_listed_guard_with_the_guard_flag() {
+        let unflagged = node("10.0.0.1:9001", "alice", &[], &[]);
+        let unlisted = node("10.1.0.1:9001", "bob", &[NodeFlag::Guard], &[]);
+        let exit = node("10.2.0.1:9001", "carol", &[NodeFlag::Exit], &["accept *:*"]);
+        let nodes = vec![unflagged, unlisted, exit];
+        assert!(select_path(&nodes, &[id(&nodes[0])], Some(&web()), 2, &ANY_SUBNET).is_err());
+        assert!(select_path(&nodes, &[], Some(&web()), 2, &ANY_SUBNET).is_err());
+        let path = select_path(&nodes, &[id(&nodes[1])], Some(&web()), 2, &ANY_SUBNET).unwrap();
+        assert_eq!(addresses(&path), ["10.1.0.1:9001", "10.2.0.1:9001"]);
+    }
+
+    #[test]
+    fn earlier_guards_are_preferred() {
+        let first = node("10.0.0.1:9001", "alice", &[NodeFlag::Guard], &[]);
+        let second = node("10.1.0.1:9001", "bob", &[NodeFlag::Guard], &[]);
+        let exit = node("10.2.0.1:9001", "carol", &[NodeFlag::Exit], &["accept *:*"]);
+        let nodes = vec![first, second, exit];
+        for _ in 0..10 {
+            let path = select_path(&nodes, &[id(&nodes[0]), id(&nodes[1])], Some(&web()), 2, &ANY_SUBNET).unwrap();
+            assert_eq!(path[0].info.address, nodes[0].info.address);
+        }
+    }
+
+    #[test]
+    fn exit_must_allow_the_target() {
+        let guard = node("10.0.0.1:9001", "alice", &[NodeFlag::Guard], &[]);
+        let mail = node("10.1.0.1:9001", "bob", &[NodeFlag::Exit], &["accept *:25"]);
+        let web_exit = node("10.2.0.1:9001", "carol", &[NodeFlag::Exit], &["accept *:80"]);
+        let nodes = vec![guard, mail, web_exit];
+        let guards = [id(&nodes[0])];
+        for _ in 0..10 {
+            let path = select_path(&nodes, &guards, Some(&web()), 2, &ANY_SUBNET).unwrap();
+            assert_eq!(path[1].info.address, nodes[2].info.address);
+        }
+        let ssh = StreamTarget::Domain("example.com".to_string(), 22);
+        let err = select_path(&nodes, &guards, Some(&ssh), 2, &ANY_SUBNET).unwrap_err();
+        assert!(err.to_string().contains("No exit's policy allows"), "{}", err);
+    }
+
+    #[test]
+    fn hops_need_distinct_operators_and_optionally_networks() {
+        let guard = node("10.0.0.1:9001", "alice", &[NodeFlag::Guard], &[]);
+        let same_operator = node("10.1.0.1:9001", "alice", &[NodeFlag::Exit], &["accept *:*"]);
+        let nodes = vec![guard.clone(), same_operator];
+        assert!(select_path(&nodes, &[id(&guard)], Some(&web()), 2, &ANY_SUBNET).is_err());
+
+        let same_network = node("10.0.0.2:9001", "bob", &[NodeFlag::Exit], &["accept *:*"]);
+        let nodes = vec![guard.clone(), same_network];
+        assert!(select_path(&nodes, &[id(&guard)], Some(&web()), 2, &ANY_SUBNET).is_ok());
+        assert!(select_path(&nodes, &[id(&guard)], Some(&web()), 2, &PathConstraints { distinct_subnets: true }).is_err());
+    }
+
+    #[test]
+    fn one_hop_circuits_need_an_exit_that_is_also_a_guard() {
+        let exit = node("10.0.0.1:9001", "alice", &[NodeFlag::Exit], &["accept *:*"]);
+        let both = node("10.1.0.1:9001", "bob", &[NodeFlag::Guard, NodeFlag::Exit], &["accept *:*"]);
+        let nodes = vec![exit, both];
+        let path = select_path(&nodes, &[id(&nodes[0]), id(&nodes[1])], Some(&web()), 1, &ANY_SUBNET).unwrap();
+        assert_eq!(addresses(&path), ["10.1.0.1:9001"]);
+    }
+
+    #[test]
+    fn nodes_on_different_hosts_fill_a_path_in_distinct_networks() {
+        // Each node registers the address it listens on, so hosts in different networks
+        // give full-length paths with the default constraints, and nodes sharing one host don't.
+        let constraints = PathConstraints { distinct_subnets: true };
+        let guard = node("192.0.2.7:9001", "alice", &[NodeFlag::Guard], &[]);
+        let middle = node("198.51.100.1:9001", "bob", &[], &[]);
+        let exit = node("203.0.113.9:9001", "carol", &[NodeFlag::Exit], &["accept *:*"]);
+        let nodes = vec![guard, middle, exit];
+        let path = select_path(&nodes, &[id(&nodes[0])], Some(&web()), 3, &constraints).unwrap();
+        assert_eq!(addresses(&path), ["192.0.2.7:9001", "198.51.100.1:9001", "203.0.113.9:9001"]);
+
+        let guard = node("127.0.0.1:9001", "alice", &[NodeFlag::Guard], &[]);
+        let middle = node("127.0.0.1:9002", "bob", &[], &[]);
+        let exit = node("127.0.0.1:9003", "carol", &[NodeFlag::Exit], &["accept *:*"]);
+        let nodes = vec![guard, middle, exit];
+        assert!(select_path(&nodes, &[id(&nodes[0])], Some(&web()), 3, &constraints).is_err());
+        assert!(select_path(&nodes, &[id(&nodes[0])], Some(&web()), 3, &ANY_SUBNET).is_ok());
+    }
+}
@@ -14,25 +14,617 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use bincode::Options;
 use serde::{Serialize, Deserialize};
-use std::net::SocketAddr;
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub type StreamID = u32;
 
+/// Every cell on an ntor circuit is exactly this long, whatever it carries.
+pub const CELL_LEN: usize = 512;
+
+/// Every relay cell starts with a two-byte `recognized` field, which is zero in plaintext,
+/// and a four-byte running digest, followed by the command, stream id and data length.
+pub const RELAY_HEADER_LEN: usize = 6;
+pub const RELAY_DIGEST_RANGE: Range<usize> = 2..6;
+const CELL_COMMAND: usize = RELAY_HEADER_LEN;
+const CELL_STREAM_ID: Range<usize> = 7..11;
+const CELL_LENGTH: Range<usize> = 11..13;
+pub const CELL_HEADER_LEN: usize = 13;
+pub const CELL_DATA_LEN: usize = CELL_LEN - CELL_HEADER_LEN;
+
+/// Version byte that preceded an [`NtorHandshake`] on the plain TCP circuits used before
+/// link connections, which nodes now refuse. Link connections start with a TLS handshake
+/// record.
+pub const HANDSHAKE_NTOR: u8 = 2;
+
+/// First byte of a TLS handshake record, which starts every link connection.
+pub const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Newest protocol version this build speaks. Bumped whenever a message on directory or
+/// link connections, or inside circuits, changes in a way older builds cannot read.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// `Destroy` cells say why the circuit is torn down.
+pub const FEATURE_DESTROY_REASONS: &str = "destroy-reasons";
+/// Streams can finish one direction at a time; see [`CircuitMessage::StreamFinished`].
+pub const FEATURE_HALF_CLOSE: &str = "half-close";
+/// Protocol features this build supports. Only those both sides have are used.
+pub const FEATURES: &[&str] = &["ntor", "links", "flow-control", FEATURE_DESTROY_REASONS, FEATURE_HALF_CLOSE];
+/// Features this build cannot work without, so a peer that lacks any is refused like one
+/// speaking the wrong version.
+pub const REQUIRED_FEATURES: &[&str] = &["ntor", "links", "flow-control"];
+
+/// Starts every hello, so a peer that predates versioning is recognized instead of misread.
+const HELLO_MAGIC: &[u8; 8] = b"GIRALNET";
+
+/// The protocol versions and features one side speaks. Sent in the hello that opens
+/// directory and link connections, and advertised by nodes in their descriptors. Its
+/// layout must never change; new capabilities are announced as features instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolSupport {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub features: Vec<String>,
+}
+
+impl ProtocolSupport {
+    /// What this build speaks.
+    pub fn ours() -> Self {
+        ProtocolSupport {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    /// The newest version and the features this build and a peer speaking `self` have in
+    /// common, or why they cannot talk.
+    pub fn negotiate(&self) -> Result<Negotiated, String> {
+        let ours = ProtocolSupport::ours();
+        let version = self.max_version.min(ours.max_version);
+        if version < self.min_version.max(ours.min_version) {
+            let upgrade = if self.min_version > ours.max_version { "this build" } else { "the peer" };
+            return Err(format!("it speaks protocol {} and this build speaks {}; upgrade {}", self, ours, upgrade));
+        }
+        let missing: Vec<&str> = REQUIRED_FEATURES.iter().copied().filter(|feature| !self.has(feature)).collect();
+        if !missing.is_empty() {
+            return Err(format!("it lacks the protocol features {}; upgrade the peer", missing.join(", ")));
+        }
+        let features = FEATURES.iter().filter(|feature| self.has(feature)).map(|feature| feature.to_string()).collect();
+        Ok(Negotiated { version, features })
+    }
+
+    fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    fn encode_hello(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut bytes = HELLO_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(self)?);
+        Ok(bytes)
+    }
+
+    /// Reads a hello frame. `None` if the frame is not a hello, as when the peer predates
+    /// versioning and starts with its first request instead.
+    pub fn decode_hello(frame: &[u8]) -> Option<Self> {
+        let body = frame.strip_prefix(HELLO_MAGIC.as_slice())?;
+        decode(body, MessageKind::Hello).ok()
+    }
+}
+
+impl fmt::Display for ProtocolSupport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min_version == self.max_version {
+            write!(f, "version {}", self.max_version)
+        } else {
+            write!(f, "versions {}-{}", self.min_version, self.max_version)
+        }
+    }
+}
+
+/// What two sides agreed to speak: the newest version both support, and the features both
+/// have. Behavior that a peer may lack is gated on [`Negotiated::has`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u16,
+    pub features: Vec<String>,
+}
+
+impl Negotiated {
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+impl fmt::Display for Negotiated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "version {}", self.version)?;
+        let optional: Vec<&str> = self.features.iter().map(String::as_str).filter(|feature| !REQUIRED_FEATURES.contains(feature)).collect();
+        if !optional.is_empty() {
+            write!(f, " with {}", optional.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends this build's hello.
+pub async fn write_hello<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<(), Box<dyn Error>> {
+    let hello = ProtocolSupport::ours().encode_hello()?;
+    write_frame(writer, &hello).await?;
+    Ok(())
+}
+
+/// Opens a connection to `peer` (how errors name it, like "the directory") by
+/// sending this build's hello and reading the peer's, returning what both speak.
+pub async fn client_hello<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, peer: &str) -> Result<Negotiated, Box<dyn Error>> {
+    write_hello(stream).await?;
+    let frame = read_handshake_frame(stream, MessageKind::Hello).await.map_err(|e| format!("No protocol hello from {} ({}); it may predate protocol versioning and need upgrading", peer, e))?;
+    let support = ProtocolSupport::decode_hello(&frame).ok_or_else(|| format!("No protocol hello from {}; it may need upgrading", peer))?;
+    Ok(support.negotiate().map_err(|e| format!("Cannot talk to {}: {}", peer, e))?)
+}
+
+/// Answers a connection from `peer` by reading its hello and sending this build's, returning
+/// what both speak. A peer that cannot be talked to still gets the hello, so it can report
+/// the mismatch on its side too.
+pub async fn server_hello<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, peer: &str) -> Result<Negotiated, Box<dyn Error>> {
+    let frame = read_handshake_frame(stream, MessageKind::Hello).await?;
+    let support = ProtocolSupport::decode_hello(&frame).ok_or_else(|| format!("No protocol hello from {}; it predates protocol versioning and needs upgrading", peer))?;
+    write_hello(stream).await?;
+    Ok(support.negotiate().map_err(|e| format!("Cannot talk to {}: {}", peer, e))?)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
-pub struct HandshakeMessage {
-    pub encrypted_aes_key: Vec<u8>,
+pub struct NtorHandshake {
+    pub node_id: [u8; 32],
+    pub client_key: [u8; 32],
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub struct NtorReply {
+    pub server_key: [u8; 32],
+    pub auth: [u8; 32],
+}
+
+/// Where a stream should connect. Hostnames travel as they are and are resolved by the
+/// exit, so they never reach the proxy's local resolver.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum StreamTarget {
+    Address(SocketAddr),
+    Domain(String, u16),
+}
+
+impl fmt::Display for StreamTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamTarget::Address(addr) => write!(f, "{}", addr),
+            StreamTarget::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// A DNS lookup the exit performs on the proxy's behalf.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ResolveQuery {
+    Hostname(String),
+    /// Reverse (PTR) lookup.
+    Address(IpAddr),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ResolveAnswer {
+    Addresses(Vec<IpAddr>),
+    Hostname(String),
+    Failed,
+}
+
+/// Why the exit could not open a stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFailReason {
+    Refused,
+    NetworkUnreachable,
+    HostUnreachable,
+    ResolveFailed,
+    ExitPolicy,
+    TimedOut,
+    Other,
+}
+
+impl fmt::Display for StreamFailReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            StreamFailReason::Refused => "connection refused",
+            StreamFailReason::NetworkUnreachable => "network unreachable",
+            StreamFailReason::HostUnreachable => "host unreachable",
+            StreamFailReason::ResolveFailed => "hostname could not be resolved",
+            StreamFailReason::ExitPolicy => "rejected by the exit policy",
+            StreamFailReason::TimedOut => "connection timed out",
+            StreamFailReason::Other => "connection failed",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Why one end closed an open stream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    /// The browser or the target closed its connection.
+    Done,
+    /// The connection to the browser or the target broke.
+    ConnectionReset,
+    /// The proxy gave up waiting for the exit to report on the stream.
+    TimedOut,
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            EndReason::Done => "connection closed",
+            EndReason::ConnectionReset => "connection reset",
+            EndReason::TimedOut => "timed out",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Why a hop could not extend the circuit to the next one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendFailReason {
+    /// The next hop could not be reached, or is not run by the operator it should be.
+    Unreachable,
+    /// The next hop did not answer the handshake in time.
+    TimedOut,
+    /// The next hop closed the connection or sent a malformed reply.
+    HandshakeFailed,
+    /// The hop already extends this circuit to another node.
+    AlreadyExtended,
+}
+
+impl fmt::Display for ExtendFailReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            ExtendFailReason::Unreachable => "next hop is unreachable",
+            ExtendFailReason::TimedOut => "next hop did not answer in time",
+            ExtendFailReason::HandshakeFailed => "next hop failed the handshake",
+            ExtendFailReason::AlreadyExtended => "circuit is already extended",
+        };
+        f.write_str(text)
+    }
+}
+
+#[derive(Debug)]
 pub enum CircuitMessage {
-    BeginStream { id: StreamID, destination: SocketAddr },
+    /// Opens a stream to `target`. `half_close` tells the exit that the proxy sends and
+    /// understands `StreamFinished`. In the cell it follows the target, so exits that
+    /// predate it ignore it, and proxies that predate it leave it out, which reads as unset.
+    BeginStream {
+        id: StreamID,
+        target: StreamTarget,
+        half_close: bool,
+    },
     StreamData { id: StreamID, data: Vec<u8> },
-    EndStream { id: StreamID },
+    /// No more data follows in this direction, like a TCP half-close. The other direction
+    /// stays open until its end finishes too.
+    StreamFinished { id: StreamID },
+    /// Closes the stream in both directions at once.
+    EndStream { id: StreamID, reason: EndReason },
+    /// Asks the exit to resolve a name, or an address for a reverse lookup, without opening
+    /// a stream. The exit answers with [`CircuitMessage::Resolved`] under the same id.
+    Resolve { id: StreamID, query: ResolveQuery },
+    Resolved { id: StreamID, answer: ResolveAnswer },
+    /// Sent by the exit once the connection for a `BeginStream` is open.
+    StreamConnected { id: StreamID },
+    /// Sent by the exit instead of `StreamConnected` when the connection could not be made.
+    StreamFailed { id: StreamID, reason: StreamFailReason },
+    /// Asks the hop it is addressed to, currently the last one, to open a circuit to
+    /// `next_hop` over its link there, passing on the handshake. `operator` is the member the
+    /// next hop must prove it is run by. The hop answers with `Extended` or `ExtendFailed`,
+    /// and from then on relays cells it does not recognize to the new hop.
+    Extend { next_hop: String, operator: String, handshake: NtorHandshake },
+    /// The new hop's answer to the handshake carried by `Extend`.
+    Extended { reply: NtorReply },
+    ExtendFailed { reason: ExtendFailReason },
+    /// Acknowledges data cells so the other end may send more; see [`crate::flow`]. Stream
+    /// id 0 acknowledges cells on the whole circuit.
+    Sendme { id: StreamID },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum OnionLayer {
-    Relay { next_hop: String, payload: Vec<u8> },
-    Exit,
-}
\ No newline at end of file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CellCommand {
+    Begin = 1,
+    Data = 2,
+    End = 3,
+    Resolve = 4,
+    Resolved = 5,
+    Connected = 6,
+    Failed = 7,
+    Extend = 8,
+    Extended = 9,
+    ExtendFailed = 10,
+    Sendme = 11,
+    Finished = 12,
+}
+
+impl TryFrom<u8> for CellCommand {
+    type Error = Box<dyn Error>;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(CellCommand::Begin),
+            2 => Ok(CellCommand::Data),
+            3 => Ok(CellCommand::End),
+            4 => Ok(CellCommand::Resolve),
+            5 => Ok(CellCommand::Resolved),
+            6 => Ok(CellCommand::Connected),
+            7 => Ok(CellCommand::Failed),
+            8 => Ok(CellCommand::Extend),
+            9 => Ok(CellCommand::Extended),
+            10 => Ok(CellCommand::ExtendFailed),
+            11 => Ok(CellCommand::Sendme),
+            12 => Ok(CellCommand::Finished),
+            other => Err(format!("Unknown cell command {}", other).into()),
+        }
+    }
+}
+
+/// Fixed-size relay cell. The unused tail of every cell is zero padding, so the size on
+/// the wire never reveals how much data a stream is sending.
+#[derive(Debug)]
+pub struct Cell {
+    pub command: CellCommand,
+    pub stream_id: StreamID,
+    pub data: Vec<u8>,
+}
+
+impl Cell {
+    /// Encodes the cell with zeroed `recognized` and digest fields, ready to be sealed.
+    pub fn encode(&self) -> [u8; CELL_LEN] {
+        debug_assert!(self.data.len() <= CELL_DATA_LEN);
+        let mut bytes = [0u8; CELL_LEN];
+        bytes[CELL_COMMAND] = self.command as u8;
+        bytes[CELL_STREAM_ID].copy_from_slice(&self.stream_id.to_be_bytes());
+        bytes[CELL_LENGTH].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+        bytes[CELL_HEADER_LEN..CELL_HEADER_LEN + self.data.len()].copy_from_slice(&self.data);
+        bytes
+    }
+
+    /// A cell carrying a single serialized value, which must fit in one cell.
+    fn with_body<T: Serialize>(command: CellCommand, stream_id: StreamID, body: &T) -> Result<Self, Box<dyn Error>> {
+        let data = bincode::serialize(body)?;
+        if data.len() > CELL_DATA_LEN {
+            return Err(format!("{:?} cell body does not fit in a cell", command).into());
+        }
+        Ok(Cell { command, stream_id, data })
+    }
+
+    pub fn decode(bytes: &[u8; CELL_LEN]) -> Result<Self, Box<dyn Error>> {
+        let command = CellCommand::try_from(bytes[CELL_COMMAND])?;
+        let stream_id = StreamID::from_be_bytes(bytes[CELL_STREAM_ID].try_into()?);
+        let length = u16::from_be_bytes(bytes[CELL_LENGTH].try_into()?) as usize;
+        if length > CELL_DATA_LEN {
+            return Err("Cell length exceeds the cell payload".into());
+        }
+        let data = bytes[CELL_HEADER_LEN..CELL_HEADER_LEN + length].to_vec();
+        Ok(Cell { command, stream_id, data })
+    }
+}
+
+impl CircuitMessage {
+    /// Splits the message into cells, fragmenting stream data across as many cells as it
+    /// takes. The receiving end reassembles simply by writing each fragment in order.
+    pub fn into_cells(self) -> Result<Vec<Cell>, Box<dyn Error>> {
+        match self {
+            CircuitMessage::BeginStream { id, target, half_close } => Ok(vec![Cell::with_body(CellCommand::Begin, id, &(target, half_close))?]),
+            CircuitMessage::StreamData { id, data } => Ok(data
+                .chunks(CELL_DATA_LEN)
+                .map(|chunk| Cell { command: CellCommand::Data, stream_id: id, data: chunk.to_vec() })
+                .collect()),
+            CircuitMessage::EndStream { id, reason } => Ok(vec![Cell::with_body(CellCommand::End, id, &reason)?]),
+            CircuitMessage::Resolve { id, query } => Ok(vec![Cell::with_body(CellCommand::Resolve, id, &query)?]),
+            CircuitMessage::Resolved { id, answer } => Ok(vec![Cell::with_body(CellCommand::Resolved, id, &answer)?]),
+            CircuitMessage::StreamConnected { id } => {
+                Ok(vec![Cell { command: CellCommand::Connected, stream_id: id, data: Vec::new() }])
+            }
+            CircuitMessage::StreamFailed { id, reason } => Ok(vec![Cell::with_body(CellCommand::Failed, id, &reason)?]),
+            // Circuit-level commands are not part of any stream and use stream id 0.
+            CircuitMessage::Extend { next_hop, operator, handshake } => {
+                Ok(vec![Cell::with_body(CellCommand::Extend, 0, &(next_hop, operator, handshake))?])
+            }
+            CircuitMessage::Extended { reply } => Ok(vec![Cell::with_body(CellCommand::Extended, 0, &reply)?]),
+            CircuitMessage::ExtendFailed { reason } => Ok(vec![Cell::with_body(CellCommand::ExtendFailed, 0, &reason)?]),
+            CircuitMessage::Sendme { id } => Ok(vec![Cell { command: CellCommand::Sendme, stream_id: id, data: Vec::new() }]),
+            CircuitMessage::StreamFinished { id } => Ok(vec![Cell { command: CellCommand::Finished, stream_id: id, data: Vec::new() }]),
+        }
+    }
+}
+
+impl TryFrom<Cell> for CircuitMessage {
+    type Error = Box<dyn Error>;
+
+    fn try_from(cell: Cell) -> Result<Self, Self::Error> {
+        let id = cell.stream_id;
+        Ok(match cell.command {
+            CellCommand::Begin => {
+                let (target, half_close) = decode(&cell.data, MessageKind::Cell)
+                    .or_else(|_| decode(&cell.data, MessageKind::Cell).map(|target| (target, false)))?;
+                CircuitMessage::BeginStream { id, target, half_close }
+            }
+            CellCommand::Data => CircuitMessage::StreamData { id, data: cell.data },
+            CellCommand::End => CircuitMessage::EndStream { id, reason: decode(&cell.data, MessageKind::Cell)? },
+            CellCommand::Resolve => CircuitMessage::Resolve { id, query: decode(&cell.data, MessageKind::Cell)? },
+            CellCommand::Resolved => CircuitMessage::Resolved { id, answer: decode(&cell.data, MessageKind::Cell)? },
+            CellCommand::Connected => CircuitMessage::StreamConnected { id },
+            CellCommand::Failed => CircuitMessage::StreamFailed { id, reason: decode(&cell.data, MessageKind::Cell)? },
+            CellCommand::Extend => {
+                let (next_hop, operator, handshake) = decode(&cell.data, MessageKind::Cell)?;
+                CircuitMessage::Extend { next_hop, operator, handshake }
+            }
+            CellCommand::Extended => CircuitMessage::Extended { reply: decode(&cell.data, MessageKind::Cell)? },
+            CellCommand::ExtendFailed => CircuitMessage::ExtendFailed { reason: decode(&cell.data, MessageKind::Cell)? },
+            CellCommand::Sendme => CircuitMessage::Sendme { id },
+            CellCommand::Finished => CircuitMessage::StreamFinished { id },
+        })
+    }
+}
+
+/// How long a peer gets to finish the handshake phase of a connection (TLS, hellos and the
+/// first request) before it is dropped, so idle connections cannot pile up.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a length-prefixed frame or bincode body read from a peer is expected to hold. Each
+/// kind has its own size limit, checked before anything is allocated for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Hello,
+    DirectoryRequest,
+    DirectoryResponse,
+    Invite,
+    /// A body inside one relay cell, or a setup message padded to one.
+    Cell,
+}
+
+impl MessageKind {
+    /// The most bytes a peer may send for one message of this kind.
+    pub fn max_len(self) -> usize {
+        match self {
+            MessageKind::Hello => 1024,
+            MessageKind::DirectoryRequest => 64 * 1024,
+            // Large enough for the consensus of a network with thousands of nodes.
+            MessageKind::DirectoryResponse => 16 * 1024 * 1024,
+            MessageKind::Invite => 1024,
+            MessageKind::Cell => CELL_LEN,
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MessageKind::Hello => "hello",
+            MessageKind::DirectoryRequest => "directory request",
+            MessageKind::DirectoryResponse => "directory response",
+            MessageKind::Invite => "invite",
+            MessageKind::Cell => "cell body",
+        })
+    }
+}
+
+/// Refuses a length announced by a peer that is too long for a `kind` message.
+pub fn check_len(len: usize, kind: MessageKind) -> io::Result<()> {
+    if len > kind.max_len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} of {} bytes exceeds the {}-byte limit", kind, len, kind.max_len()),
+        ));
+    }
+    Ok(())
+}
+
+/// Decodes a bincode `kind` message from a peer. Uses the same encoding as
+/// `bincode::serialize`, but no length inside the message can make it allocate more
+/// than the kind allows.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8], kind: MessageKind) -> Result<T, Box<dyn Error>> {
+    check_len(bytes.len(), kind)?;
+    Ok(bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(kind.max_len() as u64)
+        .deserialize(bytes)?)
+}
+
+/// Reads one `u32` length-prefixed `kind` frame, refusing it before allocating if it is
+/// longer than the kind allows.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, kind: MessageKind) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32().await? as usize;
+    check_len(len, kind)?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Like [`read_frame`], for frames read during the handshake phase, failing if the peer
+/// does not send the whole frame within [`HANDSHAKE_TIMEOUT`].
+pub async fn read_handshake_frame<R: AsyncRead + Unpin>(reader: &mut R, kind: MessageKind) -> io::Result<Vec<u8>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(reader, kind)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("timed out waiting for a {}", kind)))?
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await
+}
+
+/// Places a short setup message (such as an [`NtorReply`]) at the start of a zero-padded
+/// cell so it can travel back through relays like any other cell.
+pub fn pad_to_cell(bytes: &[u8]) -> Result<[u8; CELL_LEN], Box<dyn Error>> {
+    if bytes.len() > CELL_LEN {
+        return Err("Message does not fit in a cell".into());
+    }
+    let mut cell = [0u8; CELL_LEN];
+    cell[..bytes.len()].copy_from_slice(bytes);
+    Ok(cell)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_with(features: &[&str]) -> ProtocolSupport {
+        ProtocolSupport {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: features.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    fn begin_stream(cell: Cell) -> (StreamTarget, bool) {
+        match CircuitMessage::try_from(cell).unwrap() {
+            CircuitMessage::BeginStream { target, half_close, .. } => (target, half_close),
+            msg => panic!("expected a BeginStream, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn negotiation_keeps_only_the_features_both_sides_have() {
+        let negotiated = peer_with(REQUIRED_FEATURES).negotiate().unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert!(!negotiated.has(FEATURE_HALF_CLOSE));
+        assert!(!negotiated.has(FEATURE_DESTROY_REASONS));
+
+        let negotiated = peer_with(&["ntor", "links", "flow-control", FEATURE_HALF_CLOSE, "from-the-future"]).negotiate().unwrap();
+        assert!(negotiated.has(FEATURE_HALF_CLOSE));
+        assert!(!negotiated.has("from-the-future"));
+        assert_eq!(ProtocolSupport::ours().negotiate().unwrap().features.len(), FEATURES.len());
+    }
+
+    #[test]
+    fn peer_lacking_a_required_feature_is_refused() {
+        let err = peer_with(&["ntor", "links", FEATURE_HALF_CLOSE]).negotiate().unwrap_err();
+        assert!(err.contains("flow-control"), "{}", err);
+    }
+
+    #[test]
+    fn begin_cell_carries_half_close() {
+        let target = StreamTarget::Domain("example.com".to_string(), 80);
+        for half_close in [false, true] {
+            let msg = CircuitMessage::BeginStream { id: 7, target: target.clone(), half_close };
+            let cell = msg.into_cells().unwrap().remove(0);
+            assert_eq!(begin_stream(cell), (target.clone(), half_close));
+        }
+    }
+
+    #[test]
+    fn begin_cell_from_an_older_proxy_reads_without_half_close() {
+        let target = StreamTarget::Domain("example.com".to_string(), 80);
+        let cell = Cell::with_body(CellCommand::Begin, 7, &target).unwrap();
+        assert_eq!(begin_stream(cell), (target, false));
+    }
+}
@@ -16,247 +16,1253 @@
 
 use std::collections::HashMap;
 use std::error::Error;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
-use crate::tls_client;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::task::JoinSet;
 
+use crate::config::{self, Isolation, ListenerConfig, ProxyConfig, DEFAULT_CIRCUIT, SAFE_CIRCUIT_LENGTH};
+use crate::exit_policy::ExitPolicy;
+use crate::flow::{self, DeliveryCounter, PackageWindow, StreamQueue};
+use crate::guards::GuardSet;
+use crate::path::{self, Candidate, PathConstraints};
+use crate::link::{CircId, DestroyReason, Link, LinkCell, LinkCommand, LinkManager};
+use crate::tls_client::{self, ClientIdentity};
 use crate::{
     crypto,
-    protocol::{CircuitMessage, HandshakeMessage, OnionLayer, StreamID},
-    directory_protocol::{DirectoryRequest, DirectoryResponse, NodeInfo},
+    protocol::{self, Cell, CircuitMessage, EndReason, MessageKind, Negotiated, NtorHandshake, NtorReply, ResolveAnswer, ResolveQuery, StreamFailReason, StreamID, StreamTarget, CELL_DATA_LEN, CELL_LEN},
+    socks::{self, SocksCommand},
+    directory_protocol::{self, DirectoryRequest, DirectoryResponse, MemberAuth, NodeInfo, GET_NODES_CONTEXT},
 };
-use fast_socks5::{
-    server::{Config, DenyAuthentication, Socks5Socket},
-    Socks5Command as Command,
-};
-use rand::seq::SliceRandom;
-use rsa::RsaPublicKey;
+use rsa::{RsaPrivateKey, RsaPublicKey};
 
 
-struct CircuitManager {
+/// A built circuit and the browser streams multiplexed over it.
+struct Circuit {
+    /// Number used in log lines.
+    id: u64,
     tx: mpsc::Sender<CircuitMessage>,
+    /// Policy of the circuit's exit, deciding which streams it can carry.
+    exit_policy: ExitPolicy,
+    /// What this build and the exit both speak, as the exit's descriptor advertises it.
+    exit_protocol: Negotiated,
     next_stream_id: AtomicU32,
+    browser_streams: Mutex<HashMap<StreamID, BrowserStream>>,
+    /// Room left for data cells towards the exit, shared by all streams.
+    package_window: PackageWindow,
+    pending_resolves: Mutex<HashMap<StreamID, oneshot::Sender<ResolveAnswer>>>,
+    pending_connects: Mutex<HashMap<StreamID, oneshot::Sender<Result<(), StreamFailReason>>>>,
+    /// Set when the circuit carries its first stream. Clean circuits have none.
+    usage: OnceLock<CircuitUse>,
+    closed: AtomicBool,
+    /// Why the circuit was torn down, once it has been.
+    destroy_reason: OnceLock<DestroyReason>,
+    /// Tells the owning manager when the circuit closes, so it can replace it.
+    on_close: Arc<Notify>,
 }
 
-impl CircuitManager {
-    fn new(tx: mpsc::Sender<CircuitMessage>) -> Self {
+/// The circuit's end of a browser stream.
+struct BrowserStream {
+    /// Data from the exit, waiting to be written to the browser, and `None` once the exit
+    /// has finished sending.
+    data: StreamQueue,
+    /// Room left for data cells towards the exit on this stream.
+    window: Arc<PackageWindow>,
+}
+
+impl Drop for BrowserStream {
+    fn drop(&mut self) {
+        self.window.close();
+    }
+}
+
+/// How long a CONNECT waits for the exit to report on its stream before the circuit is
+/// given up on. The exit gives up on its own connection attempt well before this.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a SOCKS RESOLVE waits for the exit's answer.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Circuits a stream is tried on before the browser is told it failed.
+const MAX_STREAM_ATTEMPTS: usize = 3;
+
+/// Bounds of the wait between failed attempts to build a pool circuit.
+const MIN_REBUILD_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_REBUILD_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How often the pool is checked when nothing wakes it up.
+const POOL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Wait before trying again when a newer consensus could not be fetched.
+const CONSENSUS_RETRY: Duration = Duration::from_secs(60);
+
+static NEXT_CIRCUIT_ID: AtomicU64 = AtomicU64::new(1);
+
+impl Circuit {
+    fn new(tx: mpsc::Sender<CircuitMessage>, exit_policy: ExitPolicy, exit_protocol: Negotiated, on_close: Arc<Notify>) -> Self {
         Self {
+            id: NEXT_CIRCUIT_ID.fetch_add(1, Ordering::SeqCst),
             tx,
+            exit_policy,
+            exit_protocol,
             next_stream_id: AtomicU32::new(1),
+            browser_streams: Mutex::new(HashMap::new()),
+            package_window: PackageWindow::circuit(),
+            pending_resolves: Mutex::new(HashMap::new()),
+            pending_connects: Mutex::new(HashMap::new()),
+            usage: OnceLock::new(),
+            closed: AtomicBool::new(false),
+            destroy_reason: OnceLock::new(),
+            on_close,
         }
     }
     fn new_stream_id(&self) -> StreamID {
         self.next_stream_id.fetch_add(1, Ordering::SeqCst)
     }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn is_clean(&self) -> bool {
+        self.usage.get().is_none()
+    }
+
+    /// Whether a stream with `isolation` may share the circuit with the streams before it.
+    fn can_carry(&self, isolation: &IsolationKey) -> bool {
+        self.usage.get().is_none_or(|usage| &usage.isolation == isolation)
+    }
+
+    /// Marks the circuit in use by streams with `isolation`.
+    fn claim(&self, isolation: &IsolationKey) {
+        self.usage.get_or_init(|| CircuitUse {
+            since: Instant::now(),
+            isolation: isolation.clone(),
+        });
+    }
+
+    /// Whether new streams may still be attached: the circuit is open and has not been
+    /// in use for longer than `max_dirtiness`.
+    fn accepts_streams(&self, max_dirtiness: Duration) -> bool {
+        !self.is_closed() && self.usage.get().is_none_or(|usage| usage.since.elapsed() < max_dirtiness)
+    }
+
+    /// Marks the circuit dead and fails everything waiting on it. The streams' browser
+    /// connections close as their senders are dropped here.
+    async fn close(&self, reason: DestroyReason) {
+        let reason = *self.destroy_reason.get_or_init(|| reason);
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        println!("[PROXY] Circuit {} closed: {}.", self.id, reason);
+        self.pending_connects.lock().await.clear();
+        self.pending_resolves.lock().await.clear();
+        self.browser_streams.lock().await.clear();
+        self.package_window.close();
+        self.on_close.notify_one();
+    }
+}
+
+struct CircuitUse {
+    since: Instant,
+    isolation: IsolationKey,
+}
+
+/// The properties of a stream that decide which circuits it may share: streams share a
+/// circuit only if their keys are equal. Properties the listener doesn't isolate by are
+/// left empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+struct IsolationKey {
+    credentials: Option<(Vec<u8>, Vec<u8>)>,
+    client_addr: Option<IpAddr>,
+    client_port: Option<u16>,
+    dest_host: Option<String>,
+    listener: Option<String>,
+}
+
+impl IsolationKey {
+    fn new(origin: &StreamOrigin, credentials: Option<(Vec<u8>, Vec<u8>)>, dest_host: &str) -> Self {
+        let mut key = IsolationKey::default();
+        for isolation in &origin.isolate {
+            match isolation {
+                Isolation::Auth => key.credentials = credentials.clone(),
+                Isolation::ClientAddr => key.client_addr = origin.client.map(|addr| addr.ip()),
+                Isolation::ClientPort => key.client_port = origin.client.map(|addr| addr.port()),
+                Isolation::DestHost => key.dest_host = Some(dest_host.to_string()),
+                Isolation::Listener => key.listener = Some(origin.listener.clone()),
+            }
+        }
+        key
+    }
+}
+
+/// Where a browser connection came from.
+struct StreamOrigin {
+    listener: String,
+    /// The client's address; unknown for Unix domain sockets.
+    client: Option<SocketAddr>,
+    isolate: Vec<Isolation>,
+}
+
+/// Settings of a [`CircuitManager`]'s pool.
+#[derive(Clone, Copy)]
+struct PoolSettings {
+    /// Clean circuits kept built ahead of time.
+    size: usize,
+    /// How long a circuit takes new streams after its first one.
+    max_dirtiness: Duration,
 }
 
-pub async fn run(directory_addr: &str, directory_secret: &str, ca_cert_path: &str) -> Result<(), Box<dyn Error>> {
+/// The pool of circuits behind one circuit name. It keeps clean circuits ready, replaces
+/// circuits that die, and retires circuits once they have been in use for too long.
+/// Streams reuse a circuit already in use by their isolation key whose exit accepts their
+/// target, then a clean one, and a circuit is built on the spot when none fits.
+struct CircuitManager {
+    name: String,
+    network: Arc<Network>,
+    settings: PoolSettings,
+    circuits: Mutex<Vec<Arc<Circuit>>>,
+    /// Held while building on demand, so a burst of streams with the same isolation key
+    /// waits for one circuit instead of each building its own. Streams that can't share a
+    /// circuit anyway build theirs side by side.
+    building: Mutex<HashMap<IsolationKey, Arc<Mutex<()>>>>,
+    /// Wakes the maintenance task, e.g. when a circuit closes.
+    wake: Arc<Notify>,
+}
+
+impl CircuitManager {
+    /// Creates the pool and starts the task keeping it filled.
+    fn start(name: String, network: Arc<Network>, settings: PoolSettings) -> Arc<Self> {
+        let manager = Arc::new(Self {
+            name,
+            network,
+            settings,
+            circuits: Mutex::new(Vec::new()),
+            building: Mutex::new(HashMap::new()),
+            wake: Arc::new(Notify::new()),
+        });
+        tokio::spawn(manager.clone().maintain());
+        manager
+    }
+
+    /// A circuit for a stream to `target`, or for a lookup when there is none. The circuit
+    /// counts as in use by `isolation` from then on.
+    async fn get(&self, target: Option<&StreamTarget>, isolation: &IsolationKey) -> Result<Arc<Circuit>, Box<dyn Error>> {
+        if let Some(circuit) = self.find(target, isolation).await {
+            return Ok(circuit);
+        }
+        let build_lock = self.build_lock(isolation).await;
+        let _building = build_lock.lock().await;
+        // Another stream may have built a fitting circuit while we waited.
+        if let Some(circuit) = self.find(target, isolation).await {
+            return Ok(circuit);
+        }
+        match target {
+            Some(target) => println!("[PROXY] No '{}' circuit can carry {}; building one...", self.name, target),
+            None => println!("[PROXY] No '{}' circuit ready; building one...", self.name),
+        }
+        let circuit = build_circuit(&self.network, target, self.wake.clone()).await?;
+        circuit.claim(isolation);
+        self.circuits.lock().await.push(circuit.clone());
+        self.wake.notify_one();
+        Ok(circuit)
+    }
+
+    async fn build_lock(&self, isolation: &IsolationKey) -> Arc<Mutex<()>> {
+        let mut building = self.building.lock().await;
+        // Forget the locks of keys no stream is building for any more.
+        building.retain(|_, lock| Arc::strong_count(lock) > 1);
+        building.entry(isolation.clone()).or_default().clone()
+    }
+
+    async fn find(&self, target: Option<&StreamTarget>, isolation: &IsolationKey) -> Option<Arc<Circuit>> {
+        let circuits = self.circuits.lock().await;
+        let circuit = pick_circuit(&circuits, target, isolation, self.settings.max_dirtiness)?;
+        if circuit.is_clean() {
+            circuit.claim(isolation);
+            // A clean circuit was used up; build its replacement.
+            self.wake.notify_one();
+        }
+        Some(circuit)
+    }
+
+    /// Keeps `settings.size` clean circuits built, backing off while builds fail.
+    async fn maintain(self: Arc<Self>) {
+        let mut backoff = MIN_REBUILD_BACKOFF;
+        loop {
+            let clean = {
+                let mut circuits = self.circuits.lock().await;
+                // Retired circuits stay alive until their last stream ends.
+                circuits.retain(|c| c.accepts_streams(self.settings.max_dirtiness));
+                circuits.iter().filter(|c| c.is_clean()).count()
+            };
+            if clean >= self.settings.size {
+                tokio::select! {
+                    _ = self.wake.notified() => {}
+                    _ = tokio::time::sleep(POOL_CHECK_INTERVAL) => {}
+                }
+                continue;
+            }
+            match build_circuit(&self.network, None, self.wake.clone()).await.map_err(|e| e.to_string()) {
+                Ok(circuit) => {
+                    println!("[PROXY] Circuit {} ready in the '{}' pool.", circuit.id, self.name);
+                    self.circuits.lock().await.push(circuit);
+                    backoff = MIN_REBUILD_BACKOFF;
+                }
+                Err(e) => {
+                    eprintln!("[PROXY] Failed to build a '{}' circuit, retrying in {}s: {}", self.name, backoff.as_secs(), e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_REBUILD_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// The circuit a stream should use among `circuits`: one already in use by its isolation
+/// key, or else a clean one, whose exit accepts `target`. Closed and retired circuits are
+/// never picked.
+fn pick_circuit(circuits: &[Arc<Circuit>], target: Option<&StreamTarget>, isolation: &IsolationKey, max_dirtiness: Duration) -> Option<Arc<Circuit>> {
+    let fits = |c: &&Arc<Circuit>| {
+        c.accepts_streams(max_dirtiness)
+            && c.can_carry(isolation)
+            && target.is_none_or(|t| c.exit_policy.allows_target(t))
+    };
+    circuits.iter().filter(fits).find(|c| !c.is_clean())
+        .or_else(|| circuits.iter().find(fits))
+        .cloned()
+}
+
+/// The nodes listed in the newest consensus, and when it needs replacing.
+struct NodeList {
+    nodes: Vec<Candidate>,
+    /// After this a newer consensus is fetched, though this one stays in use until then.
+    fresh_until: u64,
+    /// After this no more circuits are built from it.
+    valid_until: u64,
+}
+
+/// What the proxy needs to fetch the consensus from the directory.
+struct DirectoryClient {
+    addr: String,
+    ca_cert_path: String,
+    member_key: RsaPrivateKey,
+    identity: ClientIdentity,
+    /// The pinned key the consensus must be signed with.
+    directory_key: RsaPublicKey,
+}
+
+/// The nodes circuits are built from and the rules their paths follow.
+struct Network {
+    directory: DirectoryClient,
+    /// Replaced whenever a newer consensus is fetched.
+    nodes: Mutex<Arc<NodeList>>,
+    /// Links to entry guards, shared by every circuit through the same guard.
+    links: LinkManager,
+    circuit_len: usize,
+    constraints: PathConstraints,
+    guards: Mutex<GuardSet>,
+    guard_state_file: String,
+    /// How long a guard is kept before it is rotated out.
+    guard_lifetime_secs: u64,
+}
+
+impl Network {
+    /// The nodes to build circuits from, unless the consensus listing them has expired.
+    async fn nodes(&self) -> Result<Arc<NodeList>, Box<dyn Error>> {
+        let nodes = self.nodes.lock().await.clone();
+        if directory_protocol::unix_now() > nodes.valid_until {
+            return Err("the consensus has expired and no newer one could be fetched from the directory".into());
+        }
+        Ok(nodes)
+    }
+
+    /// Fetches a newer consensus whenever the current one is no longer fresh, so nodes
+    /// that restarted with new onion keys, joined or left are seen.
+    async fn maintain(self: Arc<Self>) {
+        loop {
+            let fresh_until = self.nodes.lock().await.fresh_until;
+            let now = directory_protocol::unix_now();
+            if now < fresh_until {
+                tokio::time::sleep(Duration::from_secs(fresh_until - now)).await;
+            }
+            match self.directory.fetch_nodes().await.map_err(|e| e.to_string()) {
+                Ok(nodes) => {
+                    println!("[PROXY] Fetched a new consensus with {} nodes.", nodes.nodes.len());
+                    self.refresh_guards(&nodes.nodes).await;
+                    *self.nodes.lock().await = Arc::new(nodes);
+                }
+                Err(e) => {
+                    eprintln!("[PROXY] Failed to fetch a new consensus, retrying in {}s: {}", CONSENSUS_RETRY.as_secs(), e);
+                    tokio::time::sleep(CONSENSUS_RETRY).await;
+                }
+            }
+        }
+    }
+
+    async fn guard_failed(&self, node_id: &[u8; 32]) {
+        let mut guards = self.guards.lock().await;
+        if guards.mark_down(node_id, directory_protocol::unix_now()) {
+            eprintln!("[PROXY] Entry guard {} marked down.", &crypto::to_hex(node_id)[..16]);
+            self.save_guards(&guards);
+        }
+    }
+
+    async fn guard_succeeded(&self, node_id: &[u8; 32]) {
+        let mut guards = self.guards.lock().await;
+        if guards.mark_up(node_id) {
+            println!("[PROXY] Entry guard {} is back up.", &crypto::to_hex(node_id)[..16]);
+            self.save_guards(&guards);
+        }
+    }
+
+    /// Rotates expired and long-down guards out of the set and replaces them from the
+    /// newest consensus, so a long-running proxy doesn't wear its set down to nothing.
+    async fn refresh_guards(&self, nodes: &[Candidate]) {
+        let mut guards = self.guards.lock().await;
+        if guards.refresh(nodes, directory_protocol::unix_now(), self.guard_lifetime_secs) {
+            let guard_addrs: Vec<String> = guards.guards.iter().map(|g| g.address.to_string()).collect();
+            println!("[PROXY] Entry guards: {}", guard_addrs.join(", "));
+            self.save_guards(&guards);
+        }
+    }
+
+    fn save_guards(&self, guards: &GuardSet) {
+        if let Err(e) = guards.save(&self.guard_state_file) {
+            eprintln!("[PROXY] Failed to save entry guards to {}: {}", self.guard_state_file, e);
+        }
+    }
+}
+
+/// A bound SOCKS5 listener and the name of the circuit its connections use.
+struct ProxyListener {
+    address: String,
+    socket: ListenerSocket,
+    circuit: String,
+    isolate: Vec<Isolation>,
+}
+
+enum ListenerSocket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+pub async fn run(directory_addr: &str, ca_cert_path: &str, proxy_config: &ProxyConfig) -> Result<(), Box<dyn Error>> {
     println!("[PROXY] Starting SOCKS5 proxy...");
+    let circuit_len = proxy_config.circuit_length;
+    config::validate_circuit_length(circuit_len)?;
+    if circuit_len < SAFE_CIRCUIT_LENGTH {
+        eprintln!("[PROXY] WARNING: circuits have only {} hop(s), so a single node can learn both who you are and where you connect. Use this for testing or low-latency work only.", circuit_len);
+    }
 
-    let nodes = get_nodes_from_directory(directory_addr, directory_secret, ca_cert_path).await?;
-    println!("[PROXY] Fetched {} nodes from directory.", nodes.len());
+    // Bind first, so a bad listener address fails before any circuit is built.
+    let mut listeners = Vec::new();
+    for listener_config in listener_configs(proxy_config) {
+        listeners.push(bind_listener(&listener_config).await?);
+    }
+    if listeners.is_empty() {
+        return Err("No SOCKS5 listeners are configured. Set listen_addr or add [[proxy.listeners]].".into());
+    }
 
-    if nodes.len() < 3 {
-        return Err("Not enough nodes in directory to build a 3-hop circuit.".into());
+    let member_key = crypto::load_or_create_identity_key(&proxy_config.member_key_file, None)?;
+    let directory = DirectoryClient {
+        addr: directory_addr.to_string(),
+        ca_cert_path: ca_cert_path.to_string(),
+        identity: ClientIdentity::load(&proxy_config.member_cert_file, &member_key)?,
+        member_key,
+        directory_key: crypto::load_public_key(&proxy_config.directory_key_file)?,
+    };
+    let nodes = directory.fetch_nodes().await?;
+    println!("[PROXY] Fetched {} nodes from directory.", nodes.nodes.len());
+    if nodes.nodes.len() < circuit_len {
+        return Err(format!("Not enough ntor-capable nodes in directory to build a {}-hop circuit.", circuit_len).into());
+    }
+
+    let mut guards = GuardSet::load(&proxy_config.guard_state_file)?;
+    let lifetime_secs = proxy_config.guard_rotation_days * 24 * 60 * 60;
+    if guards.refresh(&nodes.nodes, directory_protocol::unix_now(), lifetime_secs) {
+        guards.save(&proxy_config.guard_state_file)?;
+    }
+    let guard_addrs: Vec<String> = guards.guards.iter().map(|g| g.address.to_string()).collect();
+    println!("[PROXY] Entry guards: {}", guard_addrs.join(", "));
+
+    let network = Arc::new(Network {
+        directory,
+        nodes: Mutex::new(Arc::new(nodes)),
+        links: LinkManager::new(tls_client::link_client_config(ca_cert_path, None)?),
+        circuit_len,
+        constraints: PathConstraints {
+            distinct_subnets: proxy_config.enforce_distinct_subnets,
+        },
+        guards: Mutex::new(guards),
+        guard_state_file: proxy_config.guard_state_file.clone(),
+        guard_lifetime_secs: lifetime_secs,
+    });
+    tokio::spawn(network.clone().maintain());
+    let settings = PoolSettings {
+        size: proxy_config.circuit_pool_size,
+        max_dirtiness: Duration::from_secs(proxy_config.max_circuit_dirtiness_secs),
+    };
+    let mut circuits: HashMap<String, Arc<CircuitManager>> = HashMap::new();
+    for listener in &listeners {
+        if !circuits.contains_key(&listener.circuit) {
+            println!("[PROXY] Building circuits for '{}' in the background...", listener.circuit);
+            let manager = CircuitManager::start(listener.circuit.clone(), network.clone(), settings);
+            circuits.insert(listener.circuit.clone(), manager);
+        }
+    }
+
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        println!("[PROXY] SOCKS5 proxy listening on {} (circuit '{}'). Configure your browser to use this address.", listener.address, listener.circuit);
+        let manager = circuits[&listener.circuit].clone();
+        servers.spawn(serve(listener, manager));
+    }
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// `listen_addr` on the default circuit, followed by the extra listeners.
+fn listener_configs(proxy_config: &ProxyConfig) -> Vec<ListenerConfig> {
+    let mut configs = Vec::new();
+    if !proxy_config.listen_addr.is_empty() {
+        configs.push(ListenerConfig {
+            address: proxy_config.listen_addr.clone(),
+            circuit: DEFAULT_CIRCUIT.into(),
+            isolate: proxy_config.isolate.clone(),
+        });
+    }
+    configs.extend(proxy_config.listeners.iter().cloned());
+    configs
+}
+
+async fn bind_listener(config: &ListenerConfig) -> Result<ProxyListener, Box<dyn Error>> {
+    let socket = match config.address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => {
+            use std::os::unix::fs::FileTypeExt;
+            // A socket file left behind by an earlier run would make the bind fail.
+            if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            ListenerSocket::Unix(UnixListener::bind(path)?)
+        }
+        #[cfg(not(unix))]
+        Some(_) => return Err("Unix domain socket listeners are only supported on Unix".into()),
+        None => ListenerSocket::Tcp(TcpListener::bind(&config.address).await
+            .map_err(|e| format!("Failed to listen on {}: {}", config.address, e))?),
+    };
+    Ok(ProxyListener {
+        address: config.address.clone(),
+        socket,
+        circuit: config.circuit.clone(),
+        isolate: config.isolate.clone(),
+    })
+}
+
+async fn serve(listener: ProxyListener, manager: Arc<CircuitManager>) -> io::Result<()> {
+    loop {
+        match &listener.socket {
+            ListenerSocket::Tcp(socket) => {
+                let (inbound, addr) = socket.accept().await?;
+                println!("[PROXY] Accepted browser connection from {}", addr);
+                spawn_browser_connection(inbound, manager.clone(), listener.origin(Some(addr)));
+            }
+            #[cfg(unix)]
+            ListenerSocket::Unix(socket) => {
+                let (inbound, _) = socket.accept().await?;
+                println!("[PROXY] Accepted browser connection on {}", listener.address);
+                spawn_browser_connection(inbound, manager.clone(), listener.origin(None));
+            }
+        }
+    }
+}
+
+impl ProxyListener {
+    fn origin(&self, client: Option<SocketAddr>) -> StreamOrigin {
+        StreamOrigin {
+            listener: self.address.clone(),
+            client,
+            isolate: self.isolate.clone(),
+        }
     }
+}
+
+fn spawn_browser_connection<T>(inbound: T, manager: Arc<CircuitManager>, origin: StreamOrigin)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = handle_browser_connection(inbound, manager, origin).await {
+            eprintln!("[PROXY] Error during connection handling: {}", e);
+        }
+    });
+}
 
-    println!("[PROXY] Establishing persistent circuit...");
-    let circuit_stream = connect_to_circuit(nodes).await?;
-    println!("[PROXY] Persistent circuit established.");
+/// Builds a circuit through the network, with an exit that accepts `target` if one is
+/// given, and starts the tasks moving cells over it. `on_close` is notified when the
+/// circuit dies.
+async fn build_circuit(network: &Network, target: Option<&StreamTarget>, on_close: Arc<Notify>) -> Result<Arc<Circuit>, Box<dyn Error>> {
+    let BuiltCircuit { link, circ_id, mut cells, mut forward_layers, mut backward_layers, exit_policy, exit_protocol } = connect_to_circuit(network, target).await?;
 
-    let (mut circuit_reader, mut circuit_writer) = circuit_stream.into_split();
     let (tx, mut rx) = mpsc::channel::<CircuitMessage>(128);
 
-    let browser_streams = Arc::new(Mutex::new(HashMap::<StreamID, mpsc::Sender<Vec<u8>>>::new()));
+    let circuit = Arc::new(Circuit::new(tx, exit_policy, exit_protocol, on_close));
 
+    // The tasks only hold weak references, so a circuit nobody uses any more is dropped,
+    // which ends the writer and tears the circuit down along its path.
+    let writer_circuit = Arc::downgrade(&circuit);
+    let writer_link = link.clone();
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if let Ok(bytes) = bincode::serialize(&msg) {
-                if circuit_writer.write_u32(bytes.len() as u32).await.is_err() { break; }
-                if circuit_writer.write_all(&bytes).await.is_err() { break; }
+        let reason = 'send: loop {
+            let Some(msg) = rx.recv().await else { break DestroyReason::Requested };
+            let Ok(cells) = msg.into_cells() else { continue };
+            for cell in cells {
+                let Some(bytes) = seal_cell(&mut forward_layers, &cell) else { break 'send DestroyReason::Requested };
+                if writer_link.send(LinkCell::new(circ_id, LinkCommand::Relay, bytes)).await.is_err() { break 'send DestroyReason::ConnectionLost; }
             }
+        };
+        writer_link.destroy_circuit(circ_id, reason).await;
+        if let Some(circuit) = writer_circuit.upgrade() {
+            circuit.close(reason).await;
         }
     });
 
-    let browser_streams_clone = browser_streams.clone();
+    let reader_circuit = Arc::downgrade(&circuit);
     tokio::spawn(async move {
-        loop {
-            let msg_len = match circuit_reader.read_u32().await {
-                Ok(len) => len,
-                Err(_) => break,
+        let mut delivered = DeliveryCounter::circuit();
+        // Ends with a Destroy from the entry, or when the link to it is lost.
+        let reason = loop {
+            let Some(cell) = cells.recv().await else { break DestroyReason::ConnectionLost };
+            let mut cell = match cell.command {
+                LinkCommand::Relay => cell.payload,
+                LinkCommand::Destroy => {
+                    link.remove_circuit(circ_id);
+                    break cell.destroy_reason();
+                }
+                _ => break DestroyReason::Protocol,
             };
-            let mut msg_buf = vec![0; msg_len as usize];
-            if circuit_reader.read_exact(&mut msg_buf).await.is_err() { break; }
-            if let Ok(msg) = bincode::deserialize::<CircuitMessage>(&msg_buf) {
-                let mut streams = browser_streams_clone.lock().await;
-                match msg {
-                    CircuitMessage::StreamData { id, data } => {
-                        if let Some(tx) = streams.get(&id) {
-                            let _ = tx.send(data).await;
-                        }
+            let Some(circuit) = reader_circuit.upgrade() else { return };
+            if !open_cell(&mut backward_layers, &mut cell) {
+                eprintln!("[PROXY] Dropping circuit after a relay cell failed integrity checks.");
+                break DestroyReason::Protocol;
+            }
+            let Ok(msg) = Cell::decode(&cell).and_then(CircuitMessage::try_from) else { continue };
+            match msg {
+                CircuitMessage::StreamData { id, data } => {
+                    if delivered.deliver() && circuit.tx.send(CircuitMessage::Sendme { id: 0 }).await.is_err() {
+                        break DestroyReason::ConnectionLost;
+                    }
+                    if let Some(stream) = circuit.browser_streams.lock().await.get(&id)
+                        && !flow::queue(&stream.data, Some(data))
+                    {
+                        eprintln!("[PROXY] Dropping circuit {} after the exit overran the window of stream {}.", circuit.id, id);
+                        break DestroyReason::Protocol;
+                    }
+                }
+                CircuitMessage::StreamFinished { id } => {
+                    if let Some(stream) = circuit.browser_streams.lock().await.get(&id)
+                        && !flow::queue(&stream.data, None)
+                    {
+                        eprintln!("[PROXY] Dropping circuit {} after the exit overran the window of stream {}.", circuit.id, id);
+                        break DestroyReason::Protocol;
                     }
-                    CircuitMessage::EndStream { id } => {
-                        streams.remove(&id);
+                }
+                CircuitMessage::Sendme { id: 0 } => circuit.package_window.sendme(),
+                CircuitMessage::Sendme { id } => {
+                    if let Some(stream) = circuit.browser_streams.lock().await.get(&id) {
+                        stream.window.sendme();
+                    }
+                }
+                CircuitMessage::EndStream { id, reason } => {
+                    circuit.pending_connects.lock().await.remove(&id);
+                    if circuit.browser_streams.lock().await.remove(&id).is_some() {
+                        println!("[PROXY] Exit ended stream {}: {}", id, reason);
+                    }
+                }
+                CircuitMessage::StreamConnected { id } => {
+                    if let Some(tx) = circuit.pending_connects.lock().await.remove(&id) {
+                        let _ = tx.send(Ok(()));
+                    }
+                }
+                CircuitMessage::StreamFailed { id, reason } => {
+                    if let Some(tx) = circuit.pending_connects.lock().await.remove(&id) {
+                        let _ = tx.send(Err(reason));
+                    }
+                }
+                CircuitMessage::Resolved { id, answer } => {
+                    if let Some(tx) = circuit.pending_resolves.lock().await.remove(&id) {
+                        let _ = tx.send(answer);
                     }
-                    _ => {}
                 }
+                _ => {}
             }
+        };
+        link.destroy_circuit(circ_id, reason).await;
+        if let Some(circuit) = reader_circuit.upgrade() {
+            circuit.close(reason).await;
         }
     });
 
-    let circuit_manager = Arc::new(CircuitManager::new(tx));
+    Ok(circuit)
+}
 
-    let listen_addr = "127.0.0.1:9050";
-    let listener = TcpListener::bind(listen_addr).await?;
-    println!("[PROXY] SOCKS5 proxy listening on {}. Configure your browser to use this address.", listen_addr);
-    let socks_config = Arc::new(Config::<DenyAuthentication>::default());
+impl DirectoryClient {
+    /// Fetches and verifies the consensus, keeping the nodes circuits can be built through.
+    async fn fetch_nodes(&self) -> Result<NodeList, Box<dyn Error>> {
+        println!("[PROXY] Connecting securely to directory server to fetch nodes...");
 
-    loop {
-        let (inbound, addr) = listener.accept().await?;
-        println!("[PROXY] Accepted browser connection from {}", addr);
-        let server_socket = Socks5Socket::new(inbound, socks_config.clone());
-
-        let manager_clone = circuit_manager.clone();
-        let streams_clone = browser_streams.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_browser_connection(server_socket, manager_clone, streams_clone).await {
-                eprintln!("[PROXY] Error during connection handling: {}", e);
+        let request = DirectoryRequest::GetNodes {
+            auth: MemberAuth::sign(&self.member_key, GET_NODES_CONTEXT)?,
+        };
+        let response = directory_protocol::send_request(&self.addr, &self.ca_cert_path, Some(&self.identity), &request).await?;
+        println!("[PROXY] Received node list from directory server.");
+
+        let signed = match response {
+            DirectoryResponse::Consensus(signed) => signed,
+            DirectoryResponse::Denied(reason) => {
+                return Err(format!("The directory refused the node list request: {}. If this member key is not enrolled yet, run 'giralnet enroll <invite>'.", reason).into());
             }
-        });
+            _ => return Err("Failed to get node list from directory".into()),
+        };
+        let consensus = signed.verify(&self.directory_key, directory_protocol::unix_now())?;
+
+        let total = consensus.nodes.len();
+        let nodes: Vec<Candidate> = consensus.nodes.into_iter()
+            .filter(|entry| entry.descriptor.verify().is_ok())
+            .map(|entry| Candidate {
+                info: entry.descriptor.info,
+                operator: entry.operator,
+            })
+            .collect();
+        if nodes.len() < total {
+            eprintln!("[PROXY] Ignored {} node descriptors with invalid signatures.", total - nodes.len());
+        }
+        // Circuits are only built through nodes that speak a protocol version in common with us.
+        let (nodes, incompatible): (Vec<Candidate>, Vec<Candidate>) = nodes.into_iter()
+            .partition(|node| node.info.protocol.negotiate().is_ok());
+        for node in &incompatible {
+            if let Err(reason) = node.info.protocol.negotiate() {
+                eprintln!("[PROXY] Ignoring the node at {}: {}", node.info.address, reason);
+            }
+        }
+        // Layered relay encryption needs session keys with every hop, which only ntor provides.
+        let nodes = nodes.into_iter().filter(|n| n.info.onion_key.is_some()).collect();
+        Ok(NodeList { nodes, fresh_until: consensus.fresh_until, valid_until: consensus.valid_until })
     }
 }
 
-async fn get_nodes_from_directory(dir_addr: &str, secret: &str, ca_path: &str) -> Result<Vec<NodeInfo>, Box<dyn Error>> {
-    println!("[PROXY] Connecting securely to directory server to fetch nodes...");
+async fn handle_browser_connection<T>(mut inbound: T, manager: Arc<CircuitManager>, origin: StreamOrigin) -> Result<(), Box<dyn Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let request = socks::accept(&mut inbound).await?;
+    let dest_host = match &request.target {
+        StreamTarget::Address(addr) => addr.ip().to_string(),
+        StreamTarget::Domain(host, _) => host.to_ascii_lowercase(),
+    };
+    let isolation = IsolationKey::new(&origin, request.credentials, &dest_host);
+    match request.command {
+        SocksCommand::Connect => {}
+        SocksCommand::Resolve | SocksCommand::ResolvePtr => {
+            let circuit = match manager.get(None, &isolation).await.map_err(|e| e.to_string()) {
+                Ok(circuit) => circuit,
+                Err(e) => {
+                    socks::reply(&mut inbound, socks::REPLY_GENERAL_FAILURE, None).await?;
+                    return Err(format!("No circuit for the lookup: {}", e).into());
+                }
+            };
+            return handle_resolve(inbound, circuit, request.command, request.target).await;
+        }
+    }
 
-    let mut stream = tls_client::connect(dir_addr, ca_path).await?;
-    println!("[PROXY] Connected to directory server to fetch nodes.");
+    let target = request.target;
+    // With an expired consensus, building the circuit reports the failure instead.
+    let allowed = match manager.network.nodes().await {
+        Ok(nodes) => nodes.nodes.iter().any(|n| n.info.exit_policy.allows_target(&target)),
+        Err(_) => true,
+    };
+    if !allowed {
+        socks::reply(&mut inbound, socks::REPLY_NOT_ALLOWED, None).await?;
+        return Err(format!("No exit's policy allows {}", target).into());
+    }
 
-    let request = DirectoryRequest::GetNodes {
-        secret: secret.to_string(),
+    // Circuits that die or never answer are given up on and the stream tried on another.
+    let mut opened = None;
+    let mut failure = None;
+    for attempt in 1..=MAX_STREAM_ATTEMPTS {
+        let circuit = match manager.get(Some(&target), &isolation).await.map_err(|e| e.to_string()) {
+            Ok(circuit) => circuit,
+            Err(e) => {
+                eprintln!("[PROXY] No circuit for {} (attempt {}): {}", target, attempt, e);
+                continue;
+            }
+        };
+        match open_stream(&circuit, &target).await {
+            StreamOpen::Connected(stream_id, rx, window) => {
+                opened = Some((circuit, stream_id, rx, window));
+                break;
+            }
+            StreamOpen::Failed(reason) => {
+                socks::reply(&mut inbound, socks::failure_reply(reason), None).await?;
+                return Ok(());
+            }
+            StreamOpen::CircuitFailed(reason) => {
+                match reason {
+                    Some(reason) => eprintln!("[PROXY] Circuit {} was torn down while opening a stream to {} (attempt {}): {}", circuit.id, target, attempt, reason),
+                    None => eprintln!("[PROXY] Circuit {} did not answer for a stream to {} (attempt {}).", circuit.id, target, attempt),
+                }
+                circuit.close(DestroyReason::Requested).await;
+                failure = Some(reason);
+            }
+        }
+    }
+    let Some((circuit, stream_id, mut rx_from_circuit, window)) = opened else {
+        socks::reply(&mut inbound, circuit_failure_reply(failure), None).await?;
+        return Err(format!("Could not open a stream to {} after {} attempts", target, MAX_STREAM_ATTEMPTS).into());
     };
+    socks::reply(&mut inbound, socks::REPLY_SUCCEEDED, None).await?;
+
+    let (mut browser_reader, mut browser_writer) = io::split(inbound);
     
-    let req_bytes = bincode::serialize(&request)?;
-    stream.write_u32(req_bytes.len() as u32).await?;
-    stream.write_all(&req_bytes).await?;
-    
-    let res_len = stream.read_u32().await?;
-    let mut res_buf = vec![0; res_len as usize];
-    stream.read_exact(&mut res_buf).await?;
-    
-    let response: DirectoryResponse = bincode::deserialize(&res_buf)?;
-    
-    if let DirectoryResponse::NodeList(nodes) = response {
-        Ok(nodes)
-    } else {
-        Err("Failed to get node list from directory".into())
+    // The exit may send more on the stream once what it sent has reached the browser.
+    let sendme_tx = circuit.tx.clone();
+    let mut write_task = tokio::spawn(async move {
+        let mut delivered = DeliveryCounter::stream();
+        loop {
+            match rx_from_circuit.recv().await {
+                Some(Some(data)) => {
+                    if browser_writer.write_all(&data).await.is_err() { return EndReason::ConnectionReset; }
+                    if delivered.deliver() && sendme_tx.send(CircuitMessage::Sendme { id: stream_id }).await.is_err() { return EndReason::Done; }
+                }
+                // The exit has finished sending, which the browser sees as the end of its
+                // input. It may still be sending itself.
+                Some(None) => {
+                    let _ = browser_writer.shutdown().await;
+                }
+                None => return EndReason::Done,
+            }
+        }
+    });
+
+    // Each read fills at most one data cell, so it takes one cell of both windows. The
+    // stream stays open until the exit ends it, which it does once both sides have finished
+    // sending and everything reached the target.
+    let mut read_buf = vec![0; CELL_DATA_LEN];
+    let mut browser_sending = true;
+    let reason = loop {
+        tokio::select! {
+            read = browser_reader.read(&mut read_buf), if browser_sending => {
+                let n = match read {
+                    // An exit without half-closes can only end the stream, answer or not.
+                    Ok(0) if !circuit.exit_protocol.has(protocol::FEATURE_HALF_CLOSE) => break EndReason::Done,
+                    Ok(0) => {
+                        browser_sending = false;
+                        if circuit.tx.send(CircuitMessage::StreamFinished { id: stream_id }).await.is_err() {
+                            break EndReason::Done;
+                        }
+                        continue;
+                    }
+                    Ok(n) => n,
+                    Err(_) => break EndReason::ConnectionReset,
+                };
+                if !window.take().await || !circuit.package_window.take().await {
+                    break EndReason::Done;
+                }
+                let data = read_buf[..n].to_vec();
+                if circuit.tx.send(CircuitMessage::StreamData { id: stream_id, data }).await.is_err() {
+                    break EndReason::Done;
+                }
+            }
+            // The exit ended the stream, the circuit died or the browser stopped taking data.
+            written = &mut write_task => break written.unwrap_or(EndReason::Done),
+        }
+    };
+
+    let _ = circuit.tx.send(CircuitMessage::EndStream { id: stream_id, reason }).await;
+    circuit.browser_streams.lock().await.remove(&stream_id);
+    write_task.abort();
+    match circuit.destroy_reason.get() {
+        Some(destroyed) => println!("[PROXY] Closed stream {}: circuit {} was torn down, {}", stream_id, circuit.id, destroyed),
+        None => println!("[PROXY] Closed stream {}: {}", stream_id, reason),
     }
+    Ok(())
 }
 
-async fn handle_browser_connection(
-    server_socket: Socks5Socket<TcpStream, DenyAuthentication>,
-    manager: Arc<CircuitManager>,
-    browser_streams: Arc<Mutex<HashMap<StreamID, mpsc::Sender<Vec<u8>>>>>,
-) -> Result<(), Box<dyn Error>> {
-    let mut browser_socket = server_socket.upgrade_to_socks5().await?;
+enum StreamOpen {
+    Connected(StreamID, mpsc::Receiver<Option<Vec<u8>>>, Arc<PackageWindow>),
+    /// The exit tried and reported why it could not connect.
+    Failed(StreamFailReason),
+    /// The circuit was torn down for the reason given, or did not answer in time.
+    CircuitFailed(Option<DestroyReason>),
+}
 
-    if browser_socket.cmd().as_ref() != Some(&Command::TCPConnect) {
-        return Err("Only CONNECT command is supported".into());
+/// The SOCKS reply for a stream that could not be opened because its circuits failed.
+/// `failure` is how the last circuit tried failed, if any was: torn down for a reason, or
+/// `None` if it did not answer in time.
+fn circuit_failure_reply(failure: Option<Option<DestroyReason>>) -> u8 {
+    match failure {
+        Some(None) => socks::REPLY_TTL_EXPIRED,
+        Some(Some(DestroyReason::ConnectionLost)) => socks::REPLY_NETWORK_UNREACHABLE,
+        _ => socks::REPLY_GENERAL_FAILURE,
     }
+}
 
-    let destination = browser_socket.target_addr().cloned().ok_or("Could not get target address")?;
-    let destination_addr: SocketAddr = tokio::net::lookup_host(destination.to_string()).await?.next().ok_or("DNS resolution failed")?;
+/// Asks the exit to connect to `target` and waits for its answer.
+async fn open_stream(circuit: &Circuit, target: &StreamTarget) -> StreamOpen {
+    let stream_id = circuit.new_stream_id();
+    println!("[PROXY] New stream {} to {} on circuit {}", stream_id, target, circuit.id);
 
-    let stream_id = manager.new_stream_id();
-    println!("[PROXY] New stream {} to {}", stream_id, destination_addr);
+    let (tx_to_browser, rx_from_circuit) = flow::stream_queue();
+    let window = Arc::new(PackageWindow::stream());
+    let (connected_tx, connected_rx) = oneshot::channel();
+    circuit.browser_streams.lock().await.insert(stream_id, BrowserStream { data: tx_to_browser, window: window.clone() });
+    circuit.pending_connects.lock().await.insert(stream_id, connected_tx);
+    let half_close = circuit.exit_protocol.has(protocol::FEATURE_HALF_CLOSE);
+    if circuit.tx.send(CircuitMessage::BeginStream { id: stream_id, target: target.clone(), half_close }).await.is_err() {
+        return StreamOpen::CircuitFailed(circuit.destroy_reason.get().copied());
+    }
 
-    manager.tx.send(CircuitMessage::BeginStream { id: stream_id, destination: destination_addr }).await?;
+    // The browser only hears back once the exit has reported on the connection.
+    let outcome = match tokio::time::timeout(CONNECT_TIMEOUT, connected_rx).await {
+        Ok(Ok(Ok(()))) => return StreamOpen::Connected(stream_id, rx_from_circuit, window),
+        Ok(Ok(Err(reason))) => {
+            println!("[PROXY] Stream {} to {} failed: {}", stream_id, target, reason);
+            StreamOpen::Failed(reason)
+        }
+        // Dropped unanswered: the circuit closed.
+        Ok(Err(_)) => StreamOpen::CircuitFailed(circuit.destroy_reason.get().copied()),
+        Err(_) => StreamOpen::CircuitFailed(None),
+    };
+    let reason = match outcome {
+        StreamOpen::CircuitFailed(None) => EndReason::TimedOut,
+        _ => EndReason::Done,
+    };
+    circuit.pending_connects.lock().await.remove(&stream_id);
+    circuit.browser_streams.lock().await.remove(&stream_id);
+    let _ = circuit.tx.send(CircuitMessage::EndStream { id: stream_id, reason }).await;
+    outcome
+}
 
-    let (tx_to_browser, mut rx_from_circuit) = mpsc::channel::<Vec<u8>>(128);
-    browser_streams.lock().await.insert(stream_id, tx_to_browser);
-    
-    let (mut browser_reader, mut browser_writer) = browser_socket.into_inner().into_split();
-    
-    let write_task = tokio::spawn(async move {
-        while let Some(data) = rx_from_circuit.recv().await {
-            if browser_writer.write_all(&data).await.is_err() { break; }
+/// Answers a SOCKS RESOLVE or RESOLVE_PTR by asking the exit, so the lookup never touches
+/// the local resolver.
+async fn handle_resolve<T>(mut inbound: T, circuit: Arc<Circuit>, command: SocksCommand, target: StreamTarget) -> Result<(), Box<dyn Error>>
+where
+    T: AsyncWrite + Unpin,
+{
+    let query = match (command, target) {
+        (SocksCommand::Resolve, StreamTarget::Domain(name, _)) => ResolveQuery::Hostname(name),
+        // Nothing to look up; answer with the address itself.
+        (SocksCommand::Resolve, target @ StreamTarget::Address(_)) => {
+            socks::reply(&mut inbound, socks::REPLY_SUCCEEDED, Some(&target)).await?;
+            return Ok(());
         }
-    });
+        (_, StreamTarget::Address(addr)) => ResolveQuery::Address(addr.ip()),
+        (_, StreamTarget::Domain(..)) => {
+            socks::reply(&mut inbound, socks::REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            return Err("RESOLVE_PTR needs an IP address".into());
+        }
+    };
 
-    let mut read_buf = vec![0; 4096];
-    loop {
-        let n = match browser_reader.read(&mut read_buf).await {
-            Ok(n) if n > 0 => n,
-            _ => break,
-        };
-        let data = read_buf[..n].to_vec();
-        manager.tx.send(CircuitMessage::StreamData { id: stream_id, data }).await?;
-    }
+    let id = circuit.new_stream_id();
+    println!("[PROXY] Resolving {:?} through the circuit (request {})", query, id);
+    let (answer_tx, answer_rx) = oneshot::channel();
+    circuit.pending_resolves.lock().await.insert(id, answer_tx);
+    circuit.tx.send(CircuitMessage::Resolve { id, query }).await?;
+    let answer = tokio::time::timeout(RESOLVE_TIMEOUT, answer_rx).await;
+    circuit.pending_resolves.lock().await.remove(&id);
 
-    let _ = manager.tx.send(CircuitMessage::EndStream { id: stream_id }).await;
-    browser_streams.lock().await.remove(&stream_id);
-    write_task.abort();
-    println!("[PROXY] Closed stream {}", stream_id);
+    let bound = match answer {
+        Ok(Ok(ResolveAnswer::Addresses(ips))) => ips.first().map(|ip| StreamTarget::Address(SocketAddr::new(*ip, 0))),
+        Ok(Ok(ResolveAnswer::Hostname(name))) => Some(StreamTarget::Domain(name, 0)),
+        _ => None,
+    };
+    match bound {
+        Some(bound) => socks::reply(&mut inbound, socks::REPLY_SUCCEEDED, Some(&bound)).await?,
+        None => socks::reply(&mut inbound, socks::REPLY_HOST_UNREACHABLE, None).await?,
+    }
     Ok(())
 }
 
-async fn connect_to_circuit(mut available_nodes: Vec<NodeInfo>) -> Result<TcpStream, Box<dyn Error>> {
-    let circuit_len = 3;
-    if available_nodes.len() < circuit_len {
-        return Err(format!("Not enough nodes to build a {}-hop circuit.", circuit_len).into());
+/// How long the proxy waits for each hop to join a circuit: the link to the entry and its
+/// answer to `Create`, or the answer to an `Extend`. Hops give up on reaching the next hop
+/// well before this.
+const HOP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A circuit whose hops have all answered their handshakes.
+struct BuiltCircuit {
+    link: Arc<Link>,
+    circ_id: CircId,
+    cells: mpsc::Receiver<LinkCell>,
+    forward_layers: Vec<crypto::RelayLayer>,
+    backward_layers: Vec<crypto::RelayLayer>,
+    exit_policy: ExitPolicy,
+    exit_protocol: Negotiated,
+}
+
+/// Encrypts a cell for the last hop so far, the only one that will recognize it.
+fn seal_cell(forward_layers: &mut [crypto::RelayLayer], cell: &Cell) -> Option<[u8; CELL_LEN]> {
+    let mut bytes = cell.encode();
+    forward_layers.last_mut()?.seal(&mut bytes);
+    for layer in forward_layers.iter_mut().rev() {
+        layer.apply(&mut bytes);
     }
+    Some(bytes)
+}
 
-    let mut rng = rand::thread_rng();
-    available_nodes.shuffle(&mut rng);
-    let circuit_nodes: Vec<NodeInfo> = available_nodes.into_iter().take(circuit_len).collect();
+/// Removes every backward layer from a cell and checks that the last hop so far sealed it.
+/// Only that hop ever answers the proxy, so a cell an earlier hop could have sealed is a
+/// forgery and fails like any other tampered cell.
+fn open_cell(backward_layers: &mut [crypto::RelayLayer], cell: &mut [u8; CELL_LEN]) -> bool {
+    for layer in backward_layers.iter_mut() {
+        layer.apply(cell);
+    }
+    backward_layers.last_mut().is_some_and(|layer| layer.recognize(cell))
+}
 
-    let node_addrs_str: Vec<String> = circuit_nodes.iter().map(|n| n.address.to_string()).collect();
-    let node_keys: Vec<RsaPublicKey> = circuit_nodes.iter().map(|n| n.public_key.clone()).collect();
+fn ntor_handshake(node: &NodeInfo) -> Result<(NtorHandshake, crypto::NtorClientState), Box<dyn Error>> {
+    let onion_key = node.onion_key.ok_or("Node does not publish an onion key")?;
+    let node_id = crypto::node_id(&node.public_key);
+    let state = crypto::ntor_client_start(node_id, onion_key);
+    let handshake = NtorHandshake {
+        node_id,
+        client_key: state.public_key(),
+    };
+    Ok((handshake, state))
+}
+
+/// Opens a circuit on the link to the entry and completes the handshake with it.
+async fn create_circuit(link: &Link, circ_id: CircId, cells: &mut mpsc::Receiver<LinkCell>, entry: &NodeInfo) -> Result<crypto::RelayCrypto, String> {
+    let (handshake, state) = ntor_handshake(entry).map_err(|e| e.to_string())?;
+    let payload = bincode::serialize(&handshake).map_err(|e| e.to_string())?;
+    let payload = protocol::pad_to_cell(&payload).map_err(|e| e.to_string())?;
+    link.send(LinkCell::new(circ_id, LinkCommand::Create, payload)).await.map_err(|e| e.to_string())?;
+    let reply: NtorReply = match cells.recv().await {
+        Some(cell) if cell.command == LinkCommand::Created => protocol::decode(&cell.payload, MessageKind::Cell).map_err(|e| e.to_string())?,
+        Some(cell) if cell.command == LinkCommand::Destroy => return Err(format!("the entry refused the circuit: {}", cell.destroy_reason())),
+        _ => return Err("the entry refused the circuit".into()),
+    };
+    state.finish(&reply.server_key, &reply.auth).map_err(|e| e.to_string())
+}
+
+/// Asks the last hop so far to extend the circuit and waits for its answer.
+async fn extend_circuit(link: &Link, circ_id: CircId, cells: &mut mpsc::Receiver<LinkCell>, forward_layers: &mut [crypto::RelayLayer], backward_layers: &mut [crypto::RelayLayer], extend: CircuitMessage) -> Result<NtorReply, String> {
+    for cell in extend.into_cells().map_err(|e| e.to_string())? {
+        let bytes = seal_cell(forward_layers, &cell).ok_or("the circuit has no hops")?;
+        link.send(LinkCell::new(circ_id, LinkCommand::Relay, bytes)).await.map_err(|e| format!("lost the link to the entry: {}", e))?;
+    }
+    let mut cell = match cells.recv().await {
+        Some(cell) if cell.command == LinkCommand::Relay => cell.payload,
+        Some(cell) if cell.command == LinkCommand::Destroy => return Err(format!("the circuit was torn down: {}", cell.destroy_reason())),
+        _ => return Err("the circuit was torn down".into()),
+    };
+    if !open_cell(backward_layers, &mut cell) {
+        return Err("its answer failed integrity checks".into());
+    }
+    match Cell::decode(&cell).and_then(CircuitMessage::try_from).map_err(|e| e.to_string())? {
+        CircuitMessage::Extended { reply } => Ok(reply),
+        CircuitMessage::ExtendFailed { reason } => Err(reason.to_string()),
+        other => Err(format!("unexpected answer {:?}", other)),
+    }
+}
+
+/// Builds a circuit one hop at a time: a `Create` on the link to the entry, then an
+/// `Extend` through the circuit so far for every further hop, so a failure is pinned on
+/// the hop it happened at.
+async fn connect_to_circuit(network: &Network, target: Option<&StreamTarget>) -> Result<BuiltCircuit, Box<dyn Error>> {
+    let circuit_len = network.circuit_len;
+    let nodes = network.nodes().await?;
+    let entry_guards = network.guards.lock().await.usable(&nodes.nodes, directory_protocol::unix_now());
+    let path = path::select_path(&nodes.nodes, &entry_guards, target, circuit_len, &network.constraints)?;
+    let exit_policy = path[circuit_len - 1].info.exit_policy.clone();
+    let exit_protocol = path[circuit_len - 1].info.protocol.negotiate()?;
+    let guard_id = crypto::node_id(&path[0].info.public_key);
+
+    let node_addrs_str: Vec<String> = path.iter().map(|n| n.info.address.to_string()).collect();
     
     println!("[PROXY] Building a dynamic {}-hop onion circuit via: {}", circuit_len, node_addrs_str.join(" -> "));
 
-    let exit_layer = OnionLayer::Exit;
-    let mut current_payload = bincode::serialize(&exit_layer)?;
-
-    for i in (0..circuit_len - 1).rev() {
-        let next_node_index = i + 1;
-        let aes_key = crypto::generate_aes_key();
-        let (ciphertext, nonce) = crypto::aes_encrypt(&aes_key, &current_payload);
-        let mut encrypted_payload = ciphertext;
-        encrypted_payload.extend_from_slice(&nonce);
-        let handshake = HandshakeMessage {
-            encrypted_aes_key: crypto::rsa_encrypt(&node_keys[next_node_index], &aes_key),
-        };
-        let serialized_handshake = bincode::serialize(&handshake)?;
-        let mut new_payload_for_current_node = Vec::new();
-        new_payload_for_current_node.extend_from_slice(&(serialized_handshake.len() as u32).to_be_bytes());
-        new_payload_for_current_node.extend_from_slice(&serialized_handshake);
-        new_payload_for_current_node.extend_from_slice(&(encrypted_payload.len() as u32).to_be_bytes());
-        new_payload_for_current_node.extend_from_slice(&encrypted_payload);
-        let relay_layer = OnionLayer::Relay {
-            next_hop: node_addrs_str[next_node_index].clone(),
-            payload: new_payload_for_current_node,
+    let entry = &path[0];
+    let link = match tokio::time::timeout(HOP_TIMEOUT, network.links.get(entry.info.address, &entry.operator)).await {
+        Ok(Ok(link)) => Ok(link),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no link within {} seconds", HOP_TIMEOUT.as_secs())),
+    };
+    let created = match link {
+        Ok(link) => match link.add_circuit() {
+            Some((circ_id, mut cells)) => {
+                let created = tokio::time::timeout(HOP_TIMEOUT, create_circuit(&link, circ_id, &mut cells, &entry.info))
+                    .await
+                    .unwrap_or_else(|_| Err(format!("no answer within {} seconds", HOP_TIMEOUT.as_secs())));
+                match created {
+                    Ok(relay) => Ok((link, circ_id, cells, relay)),
+                    Err(e) => {
+                        link.destroy_circuit(circ_id, DestroyReason::Requested).await;
+                        Err(e)
+                    }
+                }
+            }
+            None => Err("the link closed".into()),
+        },
+        Err(e) => Err(e),
+    };
+    let (link, circ_id, mut cells, entry_crypto) = match created {
+        Ok(created) => created,
+        Err(e) => {
+            network.guard_failed(&guard_id).await;
+            return Err(format!("Entry guard {} failed: {}", node_addrs_str[0], e).into());
+        }
+    };
+    network.guard_succeeded(&guard_id).await;
+
+    let mut forward_layers = vec![entry_crypto.forward];
+    let mut backward_layers = vec![entry_crypto.backward];
+    for i in 1..circuit_len {
+        let (handshake, state) = ntor_handshake(&path[i].info)?;
+        let extend = CircuitMessage::Extend {
+            next_hop: node_addrs_str[i].clone(),
+            operator: path[i].operator.clone(),
+            handshake,
         };
-        current_payload = bincode::serialize(&relay_layer)?;
+        let extended = tokio::time::timeout(HOP_TIMEOUT, extend_circuit(&link, circ_id, &mut cells, &mut forward_layers, &mut backward_layers, extend))
+            .await
+            .unwrap_or_else(|_| Err(format!("no answer within {} seconds", HOP_TIMEOUT.as_secs())))
+            .map_err(|e| format!("Hop {} ({}) could not extend the circuit to {}: {}", i, node_addrs_str[i - 1], node_addrs_str[i], e))
+            .and_then(|reply| {
+                state.finish(&reply.server_key, &reply.auth)
+                    .map_err(|e| format!("Hop {} ({}) failed the handshake: {}", i + 1, node_addrs_str[i], e))
+            });
+        match extended {
+            Ok(relay) => {
+                forward_layers.push(relay.forward);
+                backward_layers.push(relay.backward);
+            }
+            Err(e) => {
+                link.destroy_circuit(circ_id, DestroyReason::Requested).await;
+                return Err(e.into());
+            }
+        }
     }
+    println!("[PROXY] All hops authenticated.");
 
-    let mut stream = TcpStream::connect(&node_addrs_str[0]).await?;
-    let entry_aes_key = crypto::generate_aes_key();
-    let entry_handshake = HandshakeMessage {
-        encrypted_aes_key: crypto::rsa_encrypt(&node_keys[0], &entry_aes_key),
-    };
-    let serialized_handshake = bincode::serialize(&entry_handshake)?;
-    let (ciphertext, nonce) = crypto::aes_encrypt(&entry_aes_key, &current_payload);
-    let mut final_onion_payload = ciphertext;
-    final_onion_payload.extend_from_slice(&nonce);
-    stream.write_u32(serialized_handshake.len() as u32).await?;
-    stream.write_all(&serialized_handshake).await?;
-    stream.write_u32(final_onion_payload.len() as u32).await?;
-    stream.write_all(&final_onion_payload).await?;
-    
-    Ok(stream)
-}
\ No newline at end of file
+    Ok(BuiltCircuit { link, circ_id, cells, forward_layers, backward_layers, exit_policy, exit_protocol })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Relay crypto for a circuit of `hops` hops, as the proxy and as each node hold it.
+    fn relay_keys(hops: usize) -> (Vec<crypto::RelayCrypto>, Vec<crypto::RelayCrypto>) {
+        (0..hops).map(|_| {
+            let onion = crypto::generate_onion_key();
+            let node_id = crypto::random_bytes();
+            let state = crypto::ntor_client_start(node_id, crypto::onion_public_key(&onion));
+            let reply = crypto::ntor_server_respond(&onion, &node_id, &state.public_key()).unwrap();
+            (state.finish(&reply.server_key, &reply.auth).unwrap(), reply.relay)
+        }).unzip()
+    }
+
+    /// A cell hop `from` sends towards the proxy, through the backward layers of the hops
+    /// before it.
+    fn send_back(nodes: &mut [crypto::RelayCrypto], from: usize, msg: CircuitMessage) -> [u8; CELL_LEN] {
+        let mut bytes = msg.into_cells().unwrap().remove(0).encode();
+        nodes[from].backward.seal(&mut bytes);
+        for node in nodes[..=from].iter_mut().rev() {
+            node.backward.apply(&mut bytes);
+        }
+        bytes
+    }
+
+    const MAX_DIRTINESS: Duration = Duration::from_secs(600);
+
+    fn circuit(exit_policy: &[&str]) -> Arc<Circuit> {
+        let rules: Vec<String> = exit_policy.iter().map(|rule| rule.to_string()).collect();
+        let protocol = Negotiated { version: protocol::PROTOCOL_VERSION, features: Vec::new() };
+        let (tx, _rx) = mpsc::channel(1);
+        Arc::new(Circuit::new(tx, ExitPolicy::from_config(&rules, false).unwrap(), protocol, Arc::new(Notify::new())))
+    }
+
+    fn isolated(user: &str) -> IsolationKey {
+        IsolationKey { credentials: Some((user.as_bytes().to_vec(), Vec::new())), ..IsolationKey::default() }
+    }
+
+    /// Picks a circuit and claims it, as the manager does.
+    fn take(circuits: &[Arc<Circuit>], target: Option<&StreamTarget>, isolation: &IsolationKey) -> Option<u64> {
+        let circuit = pick_circuit(circuits, target, isolation, MAX_DIRTINESS)?;
+        circuit.claim(isolation);
+        Some(circuit.id)
+    }
+
+    #[test]
+    fn circuits_are_never_shared_across_isolation_keys() {
+        let circuits = vec![circuit(&["accept *:*"]), circuit(&["accept *:*"])];
+        let (alice, bob, carol) = (isolated("alice"), isolated("bob"), isolated("carol"));
+        let alices = take(&circuits, None, &alice).unwrap();
+        let bobs = take(&circuits, None, &bob).unwrap();
+        assert_ne!(alices, bobs);
+        // Both circuits are in use now, so a third key gets none and has to build its own.
+        assert_eq!(take(&circuits, None, &carol), None);
+        for _ in 0..3 {
+            assert_eq!(take(&circuits, None, &alice), Some(alices));
+            assert_eq!(take(&circuits, None, &bob), Some(bobs));
+        }
+    }
+
+    #[test]
+    fn streams_stay_on_their_circuit_while_its_exit_fits() {
+        let circuits = vec![circuit(&["accept *:443"]), circuit(&["accept *:*"])];
+        let alice = isolated("alice");
+        let web = StreamTarget::Domain("example.com".to_string(), 443);
+        let mail = StreamTarget::Domain("example.com".to_string(), 25);
+        let first = take(&circuits, Some(&web), &alice).unwrap();
+        assert_eq!(take(&circuits, Some(&web), &alice), Some(first));
+        // The first circuit's exit refuses mail, so the clean one is claimed for it.
+        let second = take(&circuits, Some(&mail), &alice).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn closed_and_retired_circuits_are_not_handed_out() {
+        let circuits = vec![circuit(&["accept *:*"])];
+        let alice = isolated("alice");
+        assert!(take(&circuits, None, &alice).is_some());
+        circuits[0].close(DestroyReason::ConnectionLost).await;
+        assert_eq!(take(&circuits, None, &alice), None);
+        assert_eq!(take(&circuits, None, &isolated("bob")), None);
+
+        let circuits = vec![circuit(&["accept *:*"])];
+        circuits[0].claim(&alice);
+        assert!(pick_circuit(&circuits, None, &alice, Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn cells_from_the_last_hop_open() {
+        let (proxy, mut nodes) = relay_keys(3);
+        let mut backward_layers: Vec<_> = proxy.into_iter().map(|hop| hop.backward).collect();
+        for _ in 0..3 {
+            let mut cell = send_back(&mut nodes, 2, CircuitMessage::Sendme { id: 0 });
+            assert!(open_cell(&mut backward_layers, &mut cell));
+            assert!(matches!(Cell::decode(&cell).and_then(CircuitMessage::try_from), Ok(CircuitMessage::Sendme { id: 0 })));
+        }
+    }
+
+    #[test]
+    fn cells_forged_by_an_intermediate_hop_are_refused() {
+        for forger in [0, 1] {
+            let (proxy, mut nodes) = relay_keys(3);
+            let mut backward_layers: Vec<_> = proxy.into_iter().map(|hop| hop.backward).collect();
+            let mut cell = send_back(&mut nodes, forger, CircuitMessage::StreamData { id: 1, data: b"forged".to_vec() });
+            assert!(!open_cell(&mut backward_layers, &mut cell), "hop {}", forger);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright 2025 Juan Miguel Giraldo
+//
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+
+//! The server side of SOCKS5 (RFC 1928) as the proxy speaks it to browsers: no
+//! authentication or any username and password (RFC 1929), and the CONNECT, RESOLVE and
+//! RESOLVE_PTR commands. Targets are passed on exactly as the client gave them, so
+//! hostnames are resolved by the exit. Credentials are never checked; they only tell
+//! apart streams that must not share a circuit.
+
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::{StreamFailReason, StreamTarget};
+
+const VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+
+const CMD_CONNECT: u8 = 0x01;
+/// Tor's extension for resolving a name without opening a stream.
+const CMD_RESOLVE: u8 = 0xf0;
+const CMD_RESOLVE_PTR: u8 = 0xf1;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+/// Also used for timeouts, as SOCKS5 has no dedicated code for them.
+pub const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocksCommand {
+    Connect,
+    Resolve,
+    ResolvePtr,
+}
+
+#[derive(Debug)]
+pub struct SocksRequest {
+    pub command: SocksCommand,
+    pub target: StreamTarget,
+    /// Username and password, if the client authenticated with them.
+    pub credentials: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Reads the greeting and the request. Requests the proxy cannot serve are answered with
+/// the matching reply code before the error is returned.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<SocksRequest, Box<dyn Error>> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(format!("Unsupported SOCKS version {}", header[0]).into());
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    // Clients that offer credentials send them to separate their streams, so prefer them.
+    let credentials = if methods.contains(&AUTH_USERNAME_PASSWORD) {
+        stream.write_all(&[VERSION, AUTH_USERNAME_PASSWORD]).await?;
+        Some(read_credentials(stream).await?)
+    } else if methods.contains(&AUTH_NONE) {
+        stream.write_all(&[VERSION, AUTH_NONE]).await?;
+        None
+    } else {
+        stream.write_all(&[VERSION, AUTH_NO_ACCEPTABLE]).await?;
+        return Err("The client offered no supported authentication method".into());
+    };
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(format!("Unsupported SOCKS version {}", request[0]).into());
+    }
+    let target = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            StreamTarget::Address(SocketAddr::new(Ipv4Addr::from(ip).into(), stream.read_u16().await?))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            StreamTarget::Address(SocketAddr::new(Ipv6Addr::from(ip).into(), stream.read_u16().await?))
+        }
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            let port = stream.read_u16().await?;
+            let Ok(name) = String::from_utf8(name) else {
+                reply(stream, REPLY_GENERAL_FAILURE, None).await?;
+                return Err("The requested hostname is not valid UTF-8".into());
+            };
+            StreamTarget::Domain(name, port)
+        }
+        other => {
+            reply(stream, REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            return Err(format!("Unsupported SOCKS address type {}", other).into());
+        }
+    };
+    let command = match request[1] {
+        CMD_CONNECT => SocksCommand::Connect,
+        CMD_RESOLVE => SocksCommand::Resolve,
+        CMD_RESOLVE_PTR => SocksCommand::ResolvePtr,
+        other => {
+            reply(stream, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+            return Err(format!("Unsupported SOCKS command {:#04x}", other).into());
+        }
+    };
+    Ok(SocksRequest { command, target, credentials })
+}
+
+/// Reads a username/password subnegotiation and accepts whatever it carries.
+async fn read_credentials<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let version = stream.read_u8().await?;
+    if version != USERNAME_PASSWORD_VERSION {
+        return Err(format!("Unsupported username/password version {}", version).into());
+    }
+    let mut username = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+    stream.write_all(&[USERNAME_PASSWORD_VERSION, 0x00]).await?;
+    Ok((username, password))
+}
+
+/// The reply code telling the client why the exit could not open its stream.
+pub fn failure_reply(reason: StreamFailReason) -> u8 {
+    match reason {
+        StreamFailReason::Refused => REPLY_CONNECTION_REFUSED,
+        StreamFailReason::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        StreamFailReason::HostUnreachable | StreamFailReason::ResolveFailed => REPLY_HOST_UNREACHABLE,
+        StreamFailReason::ExitPolicy => REPLY_NOT_ALLOWED,
+        StreamFailReason::TimedOut => REPLY_TTL_EXPIRED,
+        StreamFailReason::Other => REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// Writes a reply. `bound` defaults to 0.0.0.0:0 when there is nothing meaningful to report.
+pub async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8, bound: Option<&StreamTarget>) -> std::io::Result<()> {
+    let unspecified = StreamTarget::Address(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut bytes = vec![VERSION, code, 0];
+    match bound.unwrap_or(&unspecified) {
+        StreamTarget::Address(SocketAddr::V4(addr)) => {
+            bytes.push(ATYP_IPV4);
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        StreamTarget::Address(SocketAddr::V6(addr)) => {
+            bytes.push(ATYP_IPV6);
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        StreamTarget::Domain(name, port) => {
+            // Names longer than a length byte allows are cut, as nothing better fits.
+            let name = &name.as_bytes()[..name.len().min(u8::MAX as usize)];
+            bytes.push(ATYP_DOMAIN);
+            bytes.push(name.len() as u8);
+            bytes.extend_from_slice(name);
+            bytes.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    stream.write_all(&bytes).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const GREETING: &[u8] = &[VERSION, 1, AUTH_NONE];
+
+    /// Runs `accept` on a connection whose client sends `input` and closes, returning the
+    /// request, or why it was refused, and everything the proxy answered.
+    async fn accept_from(input: &[u8]) -> (Result<SocksRequest, String>, Vec<u8>) {
+        let (mut client, mut proxy) = duplex(1024);
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let result = accept(&mut proxy).await.map_err(|e| e.to_string());
+        drop(proxy);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        (result, answer)
+    }
+
+    fn encode_request(command: u8, address: &[u8]) -> Vec<u8> {
+        let mut bytes = GREETING.to_vec();
+        bytes.extend_from_slice(&[VERSION, command, 0]);
+        bytes.extend_from_slice(address);
+        bytes
+    }
+
+    fn domain(name: &str, port: u16) -> Vec<u8> {
+        let mut bytes = vec![ATYP_DOMAIN, name.len() as u8];
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&port.to_be_bytes());
+        bytes
+    }
+
+    async fn accepted(input: &[u8]) -> SocksRequest {
+        match accept_from(input).await {
+            (Ok(request), _) => request,
+            (Err(e), _) => panic!("request refused: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_to_an_ipv4_address() {
+        let (result, answer) = accept_from(&encode_request(CMD_CONNECT, &[ATYP_IPV4, 192, 0, 2, 7, 0, 80])).await;
+        let request = result.unwrap();
+        assert_eq!(answer, [VERSION, AUTH_NONE]);
+        assert_eq!(request.command, SocksCommand::Connect);
+        assert_eq!(request.target, StreamTarget::Address("192.0.2.7:80".parse().unwrap()));
+        assert!(request.credentials.is_none());
+    }
+
+    #[tokio::test]
+    async fn connect_to_an_ipv6_address() {
+        let mut address = vec![ATYP_IPV6];
+        address.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        address.extend_from_slice(&443u16.to_be_bytes());
+        let request = accepted(&encode_request(CMD_CONNECT, &address)).await;
+        assert_eq!(request.target, StreamTarget::Address("[2001:db8::1]:443".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn connect_to_a_domain_keeps_the_name() {
+        let request = accepted(&encode_request(CMD_CONNECT, &domain("example.com", 8080))).await;
+        assert_eq!(request.target, StreamTarget::Domain("example.com".to_string(), 8080));
+    }
+
+    #[tokio::test]
+    async fn resolve_commands() {
+        let request = accepted(&encode_request(CMD_RESOLVE, &domain("example.com", 0))).await;
+        assert_eq!(request.command, SocksCommand::Resolve);
+        let request = accepted(&encode_request(CMD_RESOLVE_PTR, &[ATYP_IPV4, 192, 0, 2, 7, 0, 0])).await;
+        assert_eq!(request.command, SocksCommand::ResolvePtr);
+    }
+
+    #[tokio::test]
+    async fn offered_credentials_are_preferred_and_returned() {
+        let mut input = vec![VERSION, 2, AUTH_NONE, AUTH_USERNAME_PASSWORD];
+        input.extend_from_slice(&[USERNAME_PASSWORD_VERSION, 5]);
+        input.extend_from_slice(b"alice");
+        input.extend_from_slice(&[3]);
+        input.extend_from_slice(b"tab");
+        input.extend_from_slice(&[VERSION, CMD_CONNECT, 0]);
+        input.extend_from_slice(&domain("example.com", 443));
+        let (result, answer) = accept_from(&input).await;
+        assert_eq!(result.unwrap().credentials, Some((b"alice".to_vec(), b"tab".to_vec())));
+        assert_eq!(answer, [VERSION, AUTH_USERNAME_PASSWORD, USERNAME_PASSWORD_VERSION, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn unsupported_methods_are_refused() {
+        // GSSAPI only.
+        let (result, answer) = accept_from(&[VERSION, 1, 0x01]).await;
+        assert!(result.is_err());
+        assert_eq!(answer, [VERSION, AUTH_NO_ACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn unsupported_commands_are_refused_with_their_reply() {
+        // BIND.
+        let (result, answer) = accept_from(&encode_request(0x02, &[ATYP_IPV4, 192, 0, 2, 7, 0, 80])).await;
+        assert!(result.unwrap_err().contains("command"));
+        assert_eq!(answer[2..4], [VERSION, REPLY_COMMAND_NOT_SUPPORTED]);
+
+        let (result, answer) = accept_from(&encode_request(CMD_CONNECT, &[0x05, 0, 0])).await;
+        assert!(result.unwrap_err().contains("address type"));
+        assert_eq!(answer[2..4], [VERSION, REPLY_ADDRESS_TYPE_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn other_socks_versions_are_refused() {
+        let (result, answer) = accept_from(&[4, 1, 0, 80, 192, 0, 2, 7, 0]).await;
+        assert!(result.unwrap_err().contains("version"));
+        assert!(answer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_requests_are_errors() {
+        let input = encode_request(CMD_CONNECT, &domain("example.com", 443));
+        for len in 0..input.len() {
+            let (result, _) = accept_from(&input[..len]).await;
+            assert!(result.is_err(), "accepted a request cut to {} bytes", len);
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_reports_the_bound_address() {
+        let (mut client, mut proxy) = duplex(1024);
+        let bound = StreamTarget::Address("192.0.2.7:80".parse().unwrap());
+        reply(&mut proxy, REPLY_SUCCEEDED, Some(&bound)).await.unwrap();
+        reply(&mut proxy, REPLY_GENERAL_FAILURE, None).await.unwrap();
+        drop(proxy);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, [
+            VERSION, REPLY_SUCCEEDED, 0, ATYP_IPV4, 192, 0, 2, 7, 0, 80,
+            VERSION, REPLY_GENERAL_FAILURE, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0,
+        ]);
+    }
+}
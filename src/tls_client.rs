@@ -14,17 +14,47 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use rsa::pkcs8::EncodePrivateKey;
+use rsa::RsaPrivateKey;
 use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::net::TcpStream;
-use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig};
 use tokio_rustls::rustls::ServerName;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier};
 use tokio_rustls::{TlsConnector, client::TlsStream};
 use rustls_pemfile::certs;
+use crate::{directory_protocol, tls_setup};
 
-pub async fn connect(addr: &str, ca_cert_path: &str) -> Result<TlsStream<TcpStream>, Box<dyn Error>> {
+/// The certificate and key a member presents to the directory.
+#[derive(Clone)]
+pub struct ClientIdentity {
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+}
+
+impl ClientIdentity {
+    /// Pairs the member certificate issued at enrollment with the member key it was issued for.
+    pub fn load(cert_path: &str, member_key: &RsaPrivateKey) -> Result<Self, Box<dyn Error>> {
+        if !Path::new(cert_path).exists() {
+            return Err(format!("No member certificate at {}. Run 'giralnet enroll <invite>' first", cert_path).into());
+        }
+        let cert_chain = certs(&mut BufReader::new(File::open(cert_path)?))?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let key = PrivateKey(member_key.to_pkcs8_der()?.as_bytes().to_vec());
+        Ok(ClientIdentity { cert_chain, key })
+    }
+}
+
+/// Loads the certificates in `ca_cert_path` as the only trusted roots.
+pub fn load_root_store(ca_cert_path: &str) -> Result<RootCertStore, Box<dyn Error>> {
     let mut root_cert_store = RootCertStore::empty();
     let mut pem = BufReader::new(File::open(ca_cert_path)?);
     let certs = certs(&mut pem)?;
@@ -38,12 +68,19 @@ pub async fn connect(addr: &str, ca_cert_path: &str) -> Result<TlsStream<TcpStre
         )
     });
     
-    root_cert_store.add_server_trust_anchors(trust_anchors);
+    root_cert_store.add_trust_anchors(trust_anchors);
+    Ok(root_cert_store)
+}
 
-    let config = ClientConfig::builder()
+/// Connects to `addr`, presenting `identity` as the client certificate when given.
+pub async fn connect(addr: &str, ca_cert_path: &str, identity: Option<&ClientIdentity>) -> Result<TlsStream<TcpStream>, Box<dyn Error>> {
+    let builder = ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
+        .with_root_certificates(load_root_store(ca_cert_path)?);
+    let config = match identity {
+        Some(identity) => builder.with_client_auth_cert(identity.cert_chain.clone(), identity.key.clone())?,
+        None => builder.with_no_client_auth(),
+    };
 
     let connector = TlsConnector::from(Arc::new(config));
     
@@ -54,4 +91,56 @@ pub async fn connect(addr: &str, ca_cert_path: &str) -> Result<TlsStream<TcpStre
     let tls_stream = connector.connect(domain, stream).await?;
 
     Ok(tls_stream)
-}
\ No newline at end of file
+}
+
+/// TLS settings for link connections between relays. Every relay presents its member
+/// certificate, so both ends of a link know which member runs the other. Proxies connect
+/// without one.
+pub fn link_server_config(ca_cert_path: &str, identity: &ClientIdentity) -> Result<ServerConfig, Box<dyn Error>> {
+    let client_verifier = AllowAnyAnonymousOrAuthenticatedClient::new(load_root_store(ca_cert_path)?);
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_verifier.boxed())
+        .with_single_cert(identity.cert_chain.clone(), identity.key.clone())?)
+}
+
+/// Client side of [`link_server_config`], presenting `identity` when given.
+pub fn link_client_config(ca_cert_path: &str, identity: Option<&ClientIdentity>) -> Result<ClientConfig, Box<dyn Error>> {
+    let verifier = MemberCertVerifier(AllowAnyAuthenticatedClient::new(load_root_store(ca_cert_path)?));
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    Ok(match identity {
+        Some(identity) => builder.with_client_auth_cert(identity.cert_chain.clone(), identity.key.clone())?,
+        None => builder.with_no_client_auth(),
+    })
+}
+
+/// The member a TLS peer's certificate was issued to, if it presented one.
+pub fn peer_member(peer_certificates: Option<&[Certificate]>) -> Result<Option<String>, Box<dyn Error>> {
+    match peer_certificates.and_then(|certs| certs.first()) {
+        Some(cert) => Ok(Some(directory_protocol::member_id(&tls_setup::certificate_public_key(&cert.0)?))),
+        None => Ok(None),
+    }
+}
+
+/// Accepts a relay's member certificate as its server certificate. Member certificates are
+/// issued for client authentication and name a member rather than a host, so they are
+/// checked the way the directory checks clients: issued by the team CA and still valid. The
+/// link then checks the member against the node's operator.
+struct MemberCertVerifier(AllowAnyAuthenticatedClient);
+
+impl ServerCertVerifier for MemberCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.0.verify_client_cert(end_entity, intermediates, now)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
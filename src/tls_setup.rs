@@ -14,22 +14,129 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
+use crate::crypto;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, RcgenError, RemoteKeyPair, SerialNumber, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256,
+    PKCS_RSA_SHA256,
+};
+use rsa::pkcs1::EncodeRsaPublicKey;
+use rsa::pkcs8::der::asn1::AnyRef;
+use rsa::pkcs8::der::{Decode, Reader, SliceReader, Tag, TagNumber};
+use rsa::pkcs8::SubjectPublicKeyInfoRef;
+use rsa::RsaPublicKey;
 use std::error::Error;
 use std::fs;
+use std::path::Path;
 
-pub fn generate_self_signed_cert() -> Result<(), Box<dyn Error>> {
-    println!("[TLS SETUP] Generating self-signed certificate and private key...");
+/// Subject of the team CA. Certificates are issued by a CA rebuilt from this name and the
+/// saved key, so it must never change.
+const TEAM_CA_NAME: &str = "GiralNet Team CA";
 
-    let subject_alt_names = vec!["localhost".to_string()];
+/// Creates the team CA and a directory server certificate signed by it. Members trust the
+/// CA certificate, and the directory uses the CA key to issue their client certificates.
+pub fn generate_team_ca(ca_cert_path: &str, ca_key_path: &str, cert_path: &str, key_path: &str) -> Result<(), Box<dyn Error>> {
+    if ca_cert_path == cert_path {
+        return Err(format!("The team CA certificate and the server certificate can't both be {}. Set ca_cert_path to a separate file, e.g. ca_cert.pem", cert_path).into());
+    }
+    println!("[TLS SETUP] Generating team CA and directory server certificate...");
 
-    let cert = rcgen::generate_simple_self_signed(subject_alt_names)?;
+    let ca = Certificate::from_params(team_ca_params())?;
+    fs::write(ca_cert_path, ca.serialize_pem()?)?;
+    crypto::write_private_file(ca_key_path, ca.serialize_private_key_pem().as_bytes())?;
 
-    let cert_pem = cert.serialize_pem()?;
-    let key_pem = cert.serialize_private_key_pem();
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+    params.serial_number = Some(random_serial());
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    let server = Certificate::from_params(params)?;
+    fs::write(cert_path, server.serialize_pem_with_signer(&ca)?)?;
+    crypto::write_private_file(key_path, server.serialize_private_key_pem().as_bytes())?;
 
-    fs::write("cert.pem", cert_pem.as_bytes())?;
-    fs::write("key.pem", key_pem.as_bytes())?;
-
-    println!("[TLS SETUP] Successfully generated cert.pem and key.pem");
+    println!("[TLS SETUP] Successfully generated {}, {}, {} and {}", ca_cert_path, ca_key_path, cert_path, key_path);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// The team CA, able to issue member certificates.
+pub struct TeamCa {
+    cert: Certificate,
+}
+
+impl TeamCa {
+    pub fn load(ca_key_path: &str) -> Result<Self, Box<dyn Error>> {
+        if !Path::new(ca_key_path).exists() {
+            return Err(format!("No team CA key at {}. Run 'giralnet init-ca' to create the team CA", ca_key_path).into());
+        }
+        let mut params = team_ca_params();
+        params.key_pair = Some(KeyPair::from_pem(&fs::read_to_string(ca_key_path)?)?);
+        Ok(TeamCa { cert: Certificate::from_params(params)? })
+    }
+
+    /// Issues a client certificate for an enrolled member's key, returned as PEM.
+    pub fn issue_member_cert(&self, member_id: &str, name: &str, member_key: &RsaPublicKey) -> Result<String, Box<dyn Error>> {
+        let mut params = CertificateParams::new(Vec::new());
+        params.alg = &PKCS_RSA_SHA256;
+        params.serial_number = Some(random_serial());
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, member_id);
+        params.distinguished_name.push(DnType::OrganizationalUnitName, name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params.use_authority_key_identifier_extension = true;
+        let public_key = MemberPublicKey(member_key.to_pkcs1_der()?.as_bytes().to_vec());
+        params.key_pair = Some(KeyPair::from_remote(Box::new(public_key))?);
+        Ok(Certificate::from_params(params)?.serialize_pem_with_signer(&self.cert)?)
+    }
+}
+
+fn team_ca_params() -> CertificateParams {
+    let mut params = CertificateParams::new(Vec::new());
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::DigitalSignature];
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, TEAM_CA_NAME);
+    params
+}
+
+fn random_serial() -> SerialNumber {
+    // Clear the top bit so the serial stays a positive integer.
+    let mut serial = crypto::random_bytes::<16>();
+    serial[0] &= 0x7f;
+    SerialNumber::from(serial.to_vec())
+}
+
+/// A member's public key standing in for the subject key pair of a certificate. The CA
+/// signs the certificate, so the subject key itself is never asked to sign anything.
+struct MemberPublicKey(Vec<u8>);
+
+impl RemoteKeyPair for MemberPublicKey {
+    fn public_key(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn sign(&self, _msg: &[u8]) -> Result<Vec<u8>, RcgenError> {
+        Err(RcgenError::RemoteKeyError)
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        &PKCS_RSA_SHA256
+    }
+}
+
+/// Extracts the RSA public key from a DER certificate. The TLS layer has already checked
+/// that the team CA issued the certificate, so the key identifies the member.
+pub fn certificate_public_key(cert_der: &[u8]) -> Result<RsaPublicKey, Box<dyn Error>> {
+    let certificate = AnyRef::from_der(cert_der)?;
+    let tbs: AnyRef = SliceReader::new(certificate.value())?.decode()?;
+    let mut fields = SliceReader::new(tbs.value())?;
+    // The version is optional and tagged [0]; then come the serial number, signature
+    // algorithm, issuer, validity and subject before the public key.
+    if fields.peek_tag()? == (Tag::ContextSpecific { constructed: true, number: TagNumber::N0 }) {
+        let _: AnyRef = fields.decode()?;
+    }
+    for _ in 0..5 {
+        let _: AnyRef = fields.decode()?;
+    }
+    let spki: SubjectPublicKeyInfoRef = fields.decode()?;
+    Ok(RsaPublicKey::try_from(spki).map_err(|e| format!("Certificate does not hold an RSA key: {}", e))?)
+}
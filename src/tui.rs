@@ -14,9 +14,9 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
 
-use crate::config::{Config, Mode, DirectoryConfig, NodeConfig, ProxyConfig, TlsConfig};
+use crate::config::{self, Config, Isolation, Mode, DirectoryConfig, NodeConfig, ProxyConfig, TlsConfig, MAX_CIRCUIT_LENGTH, SAFE_CIRCUIT_LENGTH};
 use crate::tls_setup;
-use dialoguer::{theme::ColorfulTheme, Select, Input, Confirm};
+use dialoguer::{theme::ColorfulTheme, Select, Input, Confirm, Password};
 use std::error::Error;
 use std::fs;
 use std::path::Path;
@@ -67,29 +67,38 @@ pub fn run_setup() -> Result<Config, Box<dyn Error>> {
     };
 
     if mode == Mode::Directory {
-        if !Path::new("cert.pem").exists() || !Path::new("key.pem").exists() {
-            println!("\nAs the Directory Server, this machine needs to create the master TLS certificates for the network.");
+        if !Path::new("ca_cert.pem").exists() || !Path::new("ca_key.pem").exists() {
+            println!("\nAs the Directory Server, this machine needs to create the team CA and TLS certificates for the network.");
             let generate_certs = Confirm::with_theme(&theme)
                 .with_prompt("Generate them now?")
                 .default(true)
                 .interact()?;
             
             if generate_certs {
-                tls_setup::generate_self_signed_cert()?;
-                println!("Success! 'cert.pem' and 'key.pem' have been created.");
-                println!("IMPORTANT: You must securely send the 'cert.pem' file to every other member of your team.");
+                tls_setup::generate_team_ca("ca_cert.pem", "ca_key.pem", "cert.pem", "key.pem")?;
+                println!("Success! 'ca_cert.pem', 'ca_key.pem', 'cert.pem' and 'key.pem' have been created.");
+                println!("IMPORTANT: You must securely send the 'ca_cert.pem' file to every other member of your team. Keep 'ca_key.pem' private.");
+                println!("Once the server has started, also send 'directory_signing_key.pub' to everyone running a Proxy.");
             } else {
                 return Err("Directory Server cannot run without TLS certificates.".into());
             }
         }
     } else {
-        if !Path::new("cert.pem").exists() {
+        if !Path::new("ca_cert.pem").exists() {
             println!("\n--- Action Required ---");
-            println!("To connect to the network, you need the 'cert.pem' file.");
+            println!("To connect to the network, you need the 'ca_cert.pem' file.");
             println!("Please get this file from the person running the Directory Server and place it in the same folder as this program.");
             println!("-----------------------");
             std::thread::sleep(std::time::Duration::from_secs(8));
-            return Err("Cannot connect without the shared 'cert.pem' file.".into());
+            return Err("Cannot connect without the shared 'ca_cert.pem' file.".into());
+        }
+        if mode == Mode::Proxy && !Path::new("directory_signing_key.pub").exists() {
+            println!("\n--- Action Required ---");
+            println!("The Proxy only trusts node lists signed by your Directory Server.");
+            println!("Please get the 'directory_signing_key.pub' file from the person running the Directory Server and place it in the same folder as this program.");
+            println!("-----------------------");
+            std::thread::sleep(std::time::Duration::from_secs(8));
+            return Err("Cannot connect without the shared 'directory_signing_key.pub' file.".into());
         }
     }
 
@@ -100,40 +109,103 @@ pub fn run_setup() -> Result<Config, Box<dyn Error>> {
         .default("localhost:8000".into())
         .interact_text()?;
 
-    let secret: String = Input::with_theme(&theme)
-        .with_prompt(" Enter the shared network secret (must be the same for all users)")
-        .validate_with(|input: &String| -> Result<(), &str> {
-            if input.len() < 8 { Err("Secret must be at least 8 characters long.") } else { Ok(()) }
-        })
-        .interact_text()?;
+    // Only the directory holds the secret; everyone else authenticates with an enrolled member key.
+    let mut secret = String::new();
+    if mode == Mode::Directory {
+        secret = Input::with_theme(&theme)
+            .with_prompt(" Create the directory secret used to sign invites (keep it private)")
+            .validate_with(|input: &String| -> Result<(), &str> {
+                if input.len() < 8 { Err("Secret must be at least 8 characters long.") } else { Ok(()) }
+            })
+            .interact_text()?;
+    }
 
     // --- NEW LOGIC TO HANDLE NODE ADDRESS ---
     let mut node_listen_addr = "127.0.0.1:9001".to_string();
+    let mut encrypt_key = false;
+    let mut exit = false;
     if mode == Mode::Node {
         node_listen_addr = Input::with_theme(&theme)
             .with_prompt(" Enter the local IP and port for this Node to listen on (e.g., 127.0.0.1:9001)")
             .with_initial_text(node_listen_addr)
             .interact_text()?;
+
+        encrypt_key = Confirm::with_theme(&theme)
+            .with_prompt(" Protect this Node's identity key with a passphrase? (You will be asked for it at every start)")
+            .default(false)
+            .interact()?;
+
+        exit = Confirm::with_theme(&theme)
+            .with_prompt(" Offer this Node as an exit? (It will connect to websites for other members, from this machine's address)")
+            .default(false)
+            .interact()?;
     }
     // --- END NEW LOGIC ---
+
+    let mut circuit_length = SAFE_CIRCUIT_LENGTH;
+    if mode == Mode::Proxy {
+        loop {
+            circuit_length = Input::with_theme(&theme)
+                .with_prompt(format!(" How many hops should each circuit have? (1-{}, 3 recommended)", MAX_CIRCUIT_LENGTH))
+                .default(SAFE_CIRCUIT_LENGTH)
+                .validate_with(|input: &usize| config::validate_circuit_length(*input))
+                .interact_text()?;
+            if circuit_length >= SAFE_CIRCUIT_LENGTH {
+                break;
+            }
+            println!("{}", format!("\n WARNING: with {} hop(s), a single node can learn both who you are and where you connect.", circuit_length).yellow().bold());
+            println!("{}", " Only use short circuits for testing or when low latency matters more than anonymity.\n".yellow());
+            let keep = Confirm::with_theme(&theme)
+                .with_prompt(" Use this circuit length anyway?")
+                .default(false)
+                .interact()?;
+            if keep {
+                break;
+            }
+        }
+    }
     
     let config = Config {
         mode,
         directory: DirectoryConfig {
             listen_addr: directory_addr,
             secret,
+            signing_key_file: "directory_signing_key".into(),
+            probe_nodes: false,
+            state_file: "directory_state.bin".into(),
+            revocation_file: "revoked_members.txt".into(),
         },
         node: NodeConfig {
             listen_addr: node_listen_addr, // Use the new, configurable address
+            advertise_addr: String::new(),
             key_file: "node_key".into(),
+            encrypt_key,
+            bandwidth: 0,
+            member_key_file: "member_key".into(),
+            member_cert_file: "member_cert.pem".into(),
+            exit_policy: if exit { vec!["accept *:*".into()] } else { Vec::new() },
+            exit_reject_private: true,
+            flags: if exit { vec!["Guard".into(), "Exit".into()] } else { vec!["Guard".into()] },
         },
         proxy: ProxyConfig {
             listen_addr: "127.0.0.1:9050".into(),
+            directory_key_file: "directory_signing_key.pub".into(),
+            member_key_file: "member_key".into(),
+            member_cert_file: "member_cert.pem".into(),
+            listeners: Vec::new(),
+            isolate: vec![Isolation::Auth],
+            enforce_distinct_subnets: true,
+            guard_state_file: "guard_state.bin".into(),
+            guard_rotation_days: 30,
+            circuit_length,
+            circuit_pool_size: 2,
+            max_circuit_dirtiness_secs: 600,
         },
         tls: TlsConfig {
-            ca_cert_path: "cert.pem".into(),
+            ca_cert_path: "ca_cert.pem".into(),
             cert_path: "cert.pem".into(),
             key_path: "key.pem".into(),
+            ca_key_path: "ca_key.pem".into(),
         },
     };
 
@@ -142,10 +214,25 @@ pub fn run_setup() -> Result<Config, Box<dyn Error>> {
 
     println!("\n-------------------------------------");
     println!("Configuration saved to 'config.toml'!");
+    if config.mode == Mode::Directory {
+        println!("Run 'giralnet invite <name>' in this folder to create an invite for each member.");
+    } else {
+        println!("Before the first start, enroll with the invite you got from the Directory Server operator:");
+        println!("    giralnet enroll <invite>");
+    }
     println!("The application will now start...");
     println!("-------------------------------------\n");
     
     std::thread::sleep(std::time::Duration::from_secs(3));
 
     Ok(config)
-}
\ No newline at end of file
+}
+
+pub fn prompt_key_passphrase(is_new_key: bool) -> Result<String, Box<dyn Error>> {
+    let theme = ColorfulTheme::default();
+    let mut prompt = Password::with_theme(&theme).with_prompt(" Enter the passphrase for this Node's keys");
+    if is_new_key {
+        prompt = prompt.with_confirmation(" Confirm the passphrase", "The passphrases don't match.");
+    }
+    Ok(prompt.interact()?)
+}